@@ -18,6 +18,18 @@ use sys_common::AsInner;
 #[allow(deprecated)]
 use os::linux::raw;
 
+#[cfg(target_os = "linux")]
+#[unstable(feature = "fs_copy_ext", issue = "0")]
+pub use sys::fs_linux::{copy_attributes, copy_cancellable, copy_ctx, copy_fd, copy_fd_with_buffer,
+                        copy_file_range_at, copy_file_range_safe, copy_hashed, copy_into_at,
+                        copy_many, copy_parallel, copy_reader_sparse, copy_stats, copy_stream,
+                        copy_tee, copy_to_open, copy_tree, copy_with_options, copy_with_progress,
+                        fiemap, is_sparse, kernel_supports_copy_file_range, sparse_segments};
+#[cfg(target_os = "linux")]
+#[unstable(feature = "fs_copy_ext", issue = "0")]
+pub use sys::fs_linux::{CopyEngine, CopyError, CopyMethod, CopyOptions, CopyStats, Extent,
+                        MMAP_MIN_LEN, PathError, SparseFileExt, SyscallCounts, TeeError};
+
 /// OS-specific extensions to [`fs::Metadata`].
 ///
 /// [`fs::Metadata`]: ../../../../std/fs/struct.Metadata.html
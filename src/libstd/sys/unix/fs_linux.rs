@@ -8,17 +8,38 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use cell::RefCell;
+#![unstable(feature = "fs_copy_ext", issue = "0")]
+// Only std::os::linux::fs exports the extensions; Android just uses copy()
+// until its libc bindings have been checked against them.
+#![cfg_attr(target_os = "android", allow(dead_code))]
+
+use cell::Cell;
 use cmp;
-use fs::{File, Metadata};
+use collections::HashMap;
+use convert::TryFrom;
+use env;
+use error;
+use ffi::{CStr, CString, OsString};
+use fmt;
+use fs::{self, File, Metadata, OpenOptions};
+use hash::Hasher;
 use io::{self, Error, ErrorKind, Read, Write};
 use libc;
 use mem;
 use os::linux::fs::MetadataExt;
-use path::Path;
+use path::{Path, PathBuf};
+use process;
 use ptr;
+use slice;
+use sync::Once;
+use sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use thread;
+use time::{Duration, Instant};
+use super::ext::ffi::OsStrExt;
+use super::ext::fs::{symlink, FileExt, FileTypeExt, OpenOptionsExt};
 use super::ext::io::AsRawFd;
-use sys::{cvt, cvt_r};
+use sys::{cvt, cvt_r, os};
+use sys_common::mutex::Mutex;
 
 unsafe fn copy_file_range(
     fd_in: libc::c_int,
@@ -39,6 +60,48 @@ unsafe fn copy_file_range(
     )
 }
 
+// _IOW(0x94, 9, int); not yet exported by libc.
+const FICLONE: libc::c_ulong = 0x40049409;
+
+// _IOW(0x94, 13, struct btrfs_ioctl_clone_range_args), also known as
+// FICLONERANGE; not yet exported by libc.
+const BTRFS_IOC_CLONE_RANGE: libc::c_ulong = 0x4020940d;
+const BTRFS_SUPER_MAGIC: u32 = 0x9123683e;
+
+#[repr(C)]
+struct BtrfsCloneRangeArgs {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+// _IOR('f', 1, long) and _IOW('f', 2, long), though the kernel only
+// reads and writes an int; not yet exported by libc.
+#[cfg(target_pointer_width = "64")]
+const FS_IOC_GETFLAGS: libc::c_ulong = 0x80086601;
+#[cfg(target_pointer_width = "64")]
+const FS_IOC_SETFLAGS: libc::c_ulong = 0x40086602;
+#[cfg(target_pointer_width = "32")]
+const FS_IOC_GETFLAGS: libc::c_ulong = 0x80046601;
+#[cfg(target_pointer_width = "32")]
+const FS_IOC_SETFLAGS: libc::c_ulong = 0x40046602;
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+const FS_APPEND_FL: libc::c_int = 0x20;
+// The flags chattr(1) sets: s, u, c, S, i, a, d, A, j, t, D, T, C and P.
+// The rest describe how the file is stored, and aren't for us to copy.
+const FS_CHATTR_FL: libc::c_int = 0x1 | 0x2 | 0x4 | 0x8 | FS_IMMUTABLE_FL | FS_APPEND_FL |
+    0x40 | 0x80 | 0x4000 | 0x8000 | 0x10000 | 0x20000 | 0x800000 | 0x20000000;
+
+// _IOWR('f', 11, struct fiemap) and its flags; not yet exported by libc.
+const FS_IOC_FIEMAP: libc::c_ulong = 0xc020660b;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+// Extents asked for in each FS_IOC_FIEMAP call.
+const FIEMAP_BATCH: usize = 64;
+
 /// Corresponds to lseek(2) `wence`. This exists in std, but doesn't support sparse-files.
 #[allow(dead_code)]
 enum Wence {
@@ -49,546 +112,6135 @@ enum Wence {
     Hole = libc::SEEK_HOLE as isize,
 }
 
+/// The result of a seek that may run off the end of the file.
 #[derive(PartialEq, Debug)]
-enum SeekOff {
+pub enum SeekOff {
+    /// The new offset from the start of the file.
     Offset(u64),
+    /// There was no data or hole at or after the requested offset.
     EOF
 }
 
-fn lseek(fd: &File, off: i64, wence: Wence) -> io::Result<SeekOff> {
-    let r = unsafe {
+// A file offset or length as passed to the kernel, which takes them as
+// a signed 64-bit loff_t. We only deal in unsigned offsets, so they are
+// converted here, rather than cast by each caller where one past
+// i64::MAX would silently turn negative.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct Offset(u64);
+
+impl Offset {
+    fn new(off: u64) -> io::Result<Offset> {
+        if off > i64::max_value() as u64 {
+            return Err(Error::new(ErrorKind::InvalidInput, "file offset out of range"));
+        }
+        Ok(Offset(off))
+    }
+
+    // From an offset returned by the kernel, which is never negative
+    // on success.
+    fn from_raw(off: libc::loff_t) -> Offset {
+        debug_assert!(off >= 0);
+        Offset(off as u64)
+    }
+
+    // The offset `n` bytes further on, if that's still in range.
+    fn add(self, n: u64) -> io::Result<Offset> {
+        match self.0.checked_add(n) {
+            Some(off) => Offset::new(off),
+            None => Err(Error::new(ErrorKind::InvalidInput, "file offset out of range")),
+        }
+    }
+
+    fn raw(self) -> libc::loff_t {
+        self.0 as libc::loff_t
+    }
+
+    fn get(self) -> u64 {
+        self.0
+    }
+}
+
+// We only ever seek forwards, so offsets are unsigned.
+fn lseek(fd: &File, off: u64, wence: Wence) -> io::Result<SeekOff> {
+    let off = Offset::new(off)?;
+    let wence = wence as libc::c_int;
+    count_syscall(|n| n.lseek += 1);
+    // A signal can interrupt a seek on some network filesystems.
+    let r = cvt_r(|| unsafe {
         libc::lseek64(
             fd.as_raw_fd(),
-            off,
-            wence as libc::c_int
+            off.raw(),
+            wence
         )
-    };
+    });
 
-    if r == -1 {
-        let err = io::Error::last_os_error();
-        match err.raw_os_error() {
-            Some(errno) if errno == libc::ENXIO => {
-                Ok(SeekOff::EOF)
-            }
-            _ => Err(err.into())
-        }
+    match r {
+        Ok(r) => Ok(SeekOff::Offset(Offset::from_raw(r).get())),
+        Err(ref err) if err.raw_os_error() == Some(libc::ENXIO) => Ok(SeekOff::EOF),
+        Err(err) => Err(err),
+    }
+}
 
-    } else {
-        Ok(SeekOff::Offset(r as u64))
+// The current position of the file's cursor.
+fn cursor(fd: &File) -> io::Result<u64> {
+    match lseek(fd, 0, Wence::Cur)? {
+        SeekOff::Offset(off) => Ok(off),
+        SeekOff::EOF => unreachable!(),
     }
+}
+
+/// Sparse-file queries on an open `File`.
+pub trait SparseFileExt {
+    /// The offset of the first data in the file, after any leading
+    /// hole, or `None` if the file is entirely a hole. Moves the file's
+    /// cursor to that offset.
+    fn first_data_offset(&self) -> io::Result<Option<u64>>;
+}
 
+impl SparseFileExt for File {
+    fn first_data_offset(&self) -> io::Result<Option<u64>> {
+        match lseek(self, 0, Wence::Data)? {
+            SeekOff::Offset(off) => Ok(Some(off)),
+            SeekOff::EOF => Ok(None),
+        }
+    }
 }
 
+// Set the length of the file to exactly `len`, discarding anything
+// beyond it.
 fn allocate_file(fd: &File, len: u64) -> io::Result<()> {
-    cvt_r(|| unsafe {libc::ftruncate64(fd.as_raw_fd(), len as i64)})?;
+    let len = Offset::new(len)?;
+    cvt_r(|| unsafe {libc::ftruncate64(fd.as_raw_fd(), len.raw())})?;
     Ok(())
 }
 
+// Extend the file to at least `len`, leaving a longer file alone.
+fn ensure_len(fd: &File, len: u64) -> io::Result<()> {
+    if fstat(fd)?.len() < len {
+        allocate_file(fd, len)?;
+    }
+    Ok(())
+}
 
-// Wrapper for copy_file_range(2) that defers file offset tracking to
-// the underlying call. See the manpage for details.
-fn copy_bytes_kernel(reader: &File, writer: &File, nbytes: usize) -> io::Result<u64> {
+// Page cache hints are only advisory, so failures are ignored.
+fn fadvise(fd: &File, advice: libc::c_int) {
     unsafe {
-        cvt(copy_file_range(reader.as_raw_fd(),
-                            ptr::null_mut(),
-                            writer.as_raw_fd(),
-                            ptr::null_mut(),
-                            nbytes,
-                            0)
-        )
+        libc::posix_fadvise(fd.as_raw_fd(), 0, 0, advice);
     }
-    .map(|v| v as u64)
 }
 
-// Slightly modified version of io::copy() that only copies a set amount of bytes.
-fn copy_bytes_uspace(mut reader: &File, mut writer: &File, nbytes: usize) -> io::Result<u64> {
-    const BLKSIZE: usize = 4 * 1024;  // Assume 4k blocks on disk.
-    let mut buf = unsafe {
-        let mut buf: [u8; BLKSIZE] = mem::uninitialized();
-        reader.initializer().initialize(&mut buf);
-        buf
-    };
+// Unlike allocate_file() this reserves real blocks on disk.
+fn preallocate_file(fd: &File, len: u64) -> io::Result<()> {
+    let len = Offset::new(len)?;
+    cvt_r(|| unsafe {
+        libc::fallocate(fd.as_raw_fd(), 0, 0, len.raw())
+    })?;
+    Ok(())
+}
 
-    let mut written = 0;
-    while written < nbytes {
-        let next = cmp::min(nbytes - written, BLKSIZE);
-        let len = match reader.read(&mut buf[..next]) {
-            Ok(0) => return Err(Error::new(ErrorKind::InvalidData,
-                                           "Source file ended prematurely.")),
-            Ok(len) => len,
-            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
-            Err(e) => return Err(e),
-        };
-        writer.write_all(&buf[..len])?;
-        written += len;
-    }
-    Ok(written as u64)
+// Deallocate a range of the file, leaving its length unchanged.
+fn punch_hole(fd: &File, off: u64, len: u64) -> io::Result<()> {
+    let off = Offset::new(off)?;
+    // The end of the range must be representable too.
+    off.add(len)?;
+    let len = Offset::new(len)?;
+    cvt_r(|| unsafe {
+        libc::fallocate(fd.as_raw_fd(),
+                        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                        off.raw(),
+                        len.raw())
+    })?;
+    Ok(())
 }
 
+// Zero a range of the file, as a hole if the filesystem supports it.
+// Leaves the cursor at an unspecified position.
+fn clear_range(mut fd: &File, off: u64, len: u64) -> io::Result<()> {
+    match punch_hole(fd, off, len) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+        result => return result,
+    }
 
-// Kernels prior to 4.5 don't have copy_file_range,so we store the
-// availability in a thread-local flag to avoid unnecessary syscalls.
-thread_local! {
-    static HAS_COPY_FILE_RANGE: RefCell<bool> = RefCell::new(true);
+    lseek(fd, off, Wence::Set)?;
+    let zeroes = vec![0; cmp::min(len, MIN_USPACE_BLKSIZE as u64) as usize];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = cmp::min(remaining, zeroes.len() as u64) as usize;
+        count_syscall(|n| n.write += 1);
+        fd.write_all(&zeroes[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
 }
 
-fn copy_bytes(reader: &File, writer: &File, uspace: bool, nbytes: u64) -> io::Result<u64> {
-    HAS_COPY_FILE_RANGE.with(|cfr| {
-        loop {
-            if uspace || !*cfr.borrow() {
-                return copy_bytes_uspace(reader, writer, nbytes as usize)
 
-            } else {
-                let result = copy_bytes_kernel(reader, writer, nbytes as usize);
-
-                if let Err(ref err) = result {
-                    match err.raw_os_error() {
-                        Some(libc::ENOSYS) | Some(libc::EPERM) => {
-                            // Flag as unavailable and retry.
-                            *cfr.borrow_mut() = false;
-                            continue;
-                        }
-                        _ => {}
-
-                    }
-                }
-                return result;
-            }
-        }
+// Wrapper for copy_file_range(2) that defers file offset tracking to
+// the underlying call. See the manpage for details.
+fn copy_bytes_kernel(reader: &File, writer: &File, nbytes: usize,
+                     flags: u32) -> io::Result<u64> {
+    count_syscall(|n| n.copy_file_range += 1);
+    cvt_r(|| unsafe {
+        copy_file_range(reader.as_raw_fd(),
+                        ptr::null_mut(),
+                        writer.as_raw_fd(),
+                        ptr::null_mut(),
+                        nbytes,
+                        flags as libc::c_uint)
     })
+    .map(|v| v as u64)
 }
 
-
-/// Copy len bytes from whereever the descriptor cursors are set.
-fn copy_range(infd: &File, outfd: &File, uspace: bool, len: u64) -> io::Result<u64> {
-    let mut written = 0;
-    while written < len {
-        let result = copy_bytes(&infd, &outfd, uspace, len - written)?;
-        written += result;
-    }
-    Ok(written)
+// Wrapper for sendfile(2), which like copy_bytes_kernel() uses and
+// advances the descriptor cursors.
+fn copy_bytes_sendfile(reader: &File, writer: &File, nbytes: usize) -> io::Result<u64> {
+    count_syscall(|n| n.sendfile += 1);
+    cvt_r(|| unsafe {
+        libc::sendfile(writer.as_raw_fd(),
+                       reader.as_raw_fd(),
+                       ptr::null_mut(),
+                       nbytes)
+    })
+    .map(|v| v as u64)
 }
 
-fn next_sparse_segments(fd: &File, pos: u64, len: u64) -> io::Result<(u64, u64)> {
-    let next_data = match lseek(fd, pos as i64, Wence::Data)? {
-        SeekOff::Offset(off) => off,
-        SeekOff::EOF => len
-    };
-    let next_hole = match lseek(fd, next_data as i64, Wence::Hole)? {
-        SeekOff::Offset(off) => off,
-        SeekOff::EOF => len
-    };
+// The kernel transfers at most this much in a single read, write or
+// copy call (MAX_RW_COUNT). It also fits in a 32-bit usize.
+const MAX_CHUNK: u64 = 0x7ffff000;
 
-    Ok((next_data, next_hole))
+// Size of the next single copy call for `nbytes` remaining. Clamped
+// before narrowing so it doesn't truncate on 32-bit targets; the
+// callers loop to copy the rest.
+fn chunk_len(nbytes: u64) -> usize {
+    cmp::min(nbytes, MAX_CHUNK) as usize
 }
 
-fn copy_sparse(infd: &File, outfd: &File, uspace: bool, len: u64) -> io::Result<u64> {
-    allocate_file(&outfd, len)?;
-
-    let mut pos = 0;
-
-    while pos < len {
-        let (next_data, next_hole) = next_sparse_segments(infd, pos, len)?;
-        lseek(infd, next_data as i64, Wence::Set)?;
-        lseek(outfd, next_data as i64, Wence::Set)?;
+// Smallest buffer used for user-space copies when the caller doesn't
+// specify one; st_blksize is typically only 4k.
+const MIN_USPACE_BLKSIZE: usize = 128 * 1024;
+
+/// Options controlling how `copy_with_options()` copies a file.
+#[derive(Clone, Debug)]
+pub struct CopyOptions {
+    /// Always copy through a user-space buffer rather than the kernel.
+    pub force_uspace: bool,
+    /// Skip holes in sparse source files rather than filling them.
+    pub preserve_sparse: bool,
+    /// Size of the buffer used for user-space copies. If `None` this is
+    /// picked from the destination's preferred block size.
+    pub block_size: Option<usize>,
+    /// Give the destination the source's owner and group, if permitted.
+    pub preserve_owner: bool,
+    /// Copy extended attributes the destination filesystem accepts.
+    pub preserve_xattrs: bool,
+    /// Copy the source's POSIX ACLs. These are applied after the mode, as
+    /// setting the mode rewrites the owner, group mask and other entries
+    /// of the access ACL from the permission bits.
+    pub preserve_acl: bool,
+    /// Give the destination the source's chattr(1) flags, such as
+    /// immutable and append-only. These are applied last of all, as
+    /// either flag stops the destination being changed any further.
+    /// Flags the destination's filesystem doesn't support are skipped.
+    pub preserve_flags: bool,
+    /// Give the destination the source's access and modification times.
+    pub preserve_times: bool,
+    /// Ignore failures to apply the permissions, owner, times or extended
+    /// attributes, e.g. on filesystems without modes, once the data has
+    /// been copied.
+    pub ignore_perm_errors: bool,
+    /// Flush the destination and its directory entry to disk before returning.
+    pub sync: bool,
+    /// Treat the source ending early as a short copy rather than an error.
+    pub allow_short: bool,
+    /// Overwrite an existing destination in place rather than truncating
+    /// it first. It is trimmed to the source length afterwards.
+    pub no_truncate: bool,
+    /// Fail with `AlreadyExists` rather than replace an existing destination.
+    pub no_clobber: bool,
+    /// Leave holes in the destination for blocks of zeroes in the source,
+    /// even if the source isn't sparse. This forces a user-space copy.
+    pub punch_zeros: bool,
+    /// Copy the file a symlink points to. If false the link itself is
+    /// recreated at the destination.
+    pub follow_symlinks: bool,
+    /// Recreate character and block device sources as device nodes with
+    /// the same device number and mode, rather than rejecting them.
+    pub copy_special: bool,
+    /// Reserve disk blocks for the whole destination before copying,
+    /// failing early with ENOSPC. The destination will not be sparse.
+    pub preallocate: bool,
+    /// Limit the average copy rate. This forces a user-space copy.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Fail with `TimedOut` if the copy is still running at this time.
+    /// This is only checked between chunks, so it can't interrupt a
+    /// blocked syscall, and a kernel copy moves much larger chunks than
+    /// a user-space one so may overshoot further.
+    pub deadline: Option<Instant>,
+    /// Re-read both files after copying and fail with `InvalidData` if
+    /// they differ.
+    pub verify: bool,
+    /// If a sparse source changes size while it's being copied, follow
+    /// it to its new length rather than copying exactly the length it
+    /// had at the start.
+    pub follow_growth: bool,
+    /// Flags passed to copy_file_range(2). None are currently defined;
+    /// if the kernel rejects them with EINVAL the data is copied in
+    /// user-space instead.
+    pub copy_flags: u32,
+    /// Ranges smaller than this are copied in user-space, where a read
+    /// and a write are cheaper than setting up a kernel copy.
+    pub kernel_copy_min_bytes: u64,
+    /// If a `copy_cancellable()` is cancelled, remove the destination
+    /// when it was created by the copy.
+    pub remove_partial: bool,
+    /// Remove the destination if copying the data fails, e.g. with
+    /// ENOSPC, unless it was an existing file being updated in place.
+    pub remove_on_error: bool,
+    /// Work out how the copy would be done and return it as `CopyStats`,
+    /// without creating or modifying the destination.
+    pub dry_run: bool,
+    /// Hint that the files are read sequentially, and drop the source
+    /// from the page cache afterwards, so that a one-off copy of a large
+    /// file doesn't evict more useful data.
+    pub drop_cache: bool,
+    /// If the source is a FIFO, read from it until every writer has
+    /// closed it and copy what was read into a regular destination file,
+    /// rather than failing because it isn't a regular file.
+    pub drain_fifo: bool,
+    /// Open both files with `O_DIRECT` so that the copy bypasses the page
+    /// cache. This always copies through an aligned user-space buffer and
+    /// fills any holes. The unaligned tail of the file is written without
+    /// `O_DIRECT`, and the files are left without it afterwards.
+    pub direct_io: bool,
+    /// Try the kernel copies even when the files are on different
+    /// filesystems, as newer kernels allow. Otherwise such copies go
+    /// straight to user-space. If copy_file_range refuses with EXDEV the
+    /// rest of the copy falls back to sendfile or user-space.
+    pub allow_xmount_kernel_copy: bool,
+    /// Count the syscalls made while copying, and report them in
+    /// `CopyStats::syscalls`.
+    pub instrument: bool,
+    /// Copy into a temporary file beside the destination, named
+    /// `.<name>.tmp.<pid>`, and only once it's complete and synced rename
+    /// it over the destination. Readers then see either the old file or
+    /// the whole new one, never a partial copy. The temporary file is
+    /// removed if the copy fails. A symlink at the destination is
+    /// replaced rather than followed.
+    pub atomic: bool,
+    /// Holes in a sparse source shorter than this are copied as zeroes
+    /// along with the data either side, rather than seeked over. This
+    /// goes for holes at the start and end of the file too, so only the
+    /// longer holes are left in the destination. If `None` this is the
+    /// destination's `st_blksize`.
+    pub min_hole_len: Option<u64>,
+    /// Skip the copy, returning 0, if the destination exists and was
+    /// modified no earlier than the source. Modification times are
+    /// compared to the nanosecond, so only a strictly newer source is
+    /// copied.
+    pub update_only: bool,
+    /// Walk the source's data and holes as for a sparse file even if it
+    /// doesn't look sparse. A dense file is then just one data segment,
+    /// so this mostly matters with `punch_zeros`, to make a sparse copy of
+    /// a dense source.
+    pub force_sparse: bool,
+    /// Fail with `ErrorKind::Other` rather than make a destination longer
+    /// than this. It's the logical length that counts, holes included,
+    /// since that's what anything reading the copy back sees even if a
+    /// sparse copy takes up much less space. A source of known length is
+    /// rejected before anything is written; a FIFO is stopped once it
+    /// reaches the limit.
+    pub max_output_bytes: Option<u64>,
+    /// Find a sparse source's data from its extent map, via `fiemap()`,
+    /// rather than by seeking. Preallocated extents that were never
+    /// written are then skipped like holes, so this also applies to
+    /// sources that don't look sparse. Falls back to seeking where the
+    /// filesystem can't map extents, and isn't used with `follow_growth`
+    /// as the map is only taken once.
+    pub use_fiemap: bool,
+    /// Shortest run of zeroes that `punch_zeros` leaves as a hole. Shorter
+    /// runs are written out, as the filesystem allocates whole blocks
+    /// anyway. Runs are looked for a whole multiple of this at a time in
+    /// each user-space buffer, so it should be no larger than
+    /// `block_size`. If `None` this is the destination's `st_blksize`.
+    pub hole_granularity: Option<u64>,
+    /// Create any missing directories leading to the destination, as
+    /// `mkdir -p` would, before creating it.
+    pub create_parents: bool,
+    /// Write into an existing destination that is already at least as
+    /// long as the source, such as a fixed-size container or block-backed
+    /// image, without resizing it. Only the source's data is written, at
+    /// the same offsets; whatever the destination holds over the source's
+    /// holes and beyond its end is left alone, so it should already be
+    /// zeroes there. Fails with `InvalidInput` if the destination is
+    /// shorter than the source.
+    pub presized: bool,
+    /// Write out a sparse source's holes as zeroes, so that the
+    /// destination has every block allocated, for tools that can't handle
+    /// sparse files. This is the opposite of `preserve_sparse` and
+    /// `punch_zeros`, and overrides both. It also forces a user-space
+    /// copy, as the kernel may clone extents or leave holes itself.
+    pub materialize_holes: bool,
+    /// Experimental: in a user-space copy of a regular file of at least
+    /// `MMAP_MIN_LEN` bytes, map the source into memory and write from
+    /// the mapping, saving a copy into the read buffer. The mapping only
+    /// covers the source's length when the copy started, but if the
+    /// source is truncated while it's being copied the process is sent
+    /// `SIGBUS`, which kills it unless handled. Only use this on sources
+    /// nothing else will shrink.
+    pub use_mmap: bool,
+}
 
-        let _written = copy_range(infd, outfd, uspace, next_hole - next_data)?;
-        pos = next_hole;
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions {
+            force_uspace: false,
+            preserve_sparse: true,
+            block_size: None,
+            preserve_owner: false,
+            preserve_xattrs: false,
+            preserve_acl: false,
+            preserve_flags: false,
+            preserve_times: false,
+            ignore_perm_errors: false,
+            sync: false,
+            allow_short: false,
+            no_truncate: false,
+            no_clobber: false,
+            punch_zeros: false,
+            follow_symlinks: true,
+            copy_special: false,
+            preallocate: false,
+            max_bytes_per_sec: None,
+            deadline: None,
+            verify: false,
+            follow_growth: false,
+            copy_flags: 0,
+            kernel_copy_min_bytes: 64 * 1024,
+            remove_partial: false,
+            remove_on_error: false,
+            dry_run: false,
+            drop_cache: false,
+            drain_fifo: false,
+            direct_io: false,
+            allow_xmount_kernel_copy: false,
+            instrument: false,
+            atomic: false,
+            min_hole_len: None,
+            update_only: false,
+            force_sparse: false,
+            max_output_bytes: None,
+            use_fiemap: false,
+            hole_granularity: None,
+            create_parents: false,
+            presized: false,
+            materialize_holes: false,
+            use_mmap: false,
+        }
     }
+}
 
-    Ok(len)
+/// How the data of a copy was transferred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// copy_file_range(2), or a copy-on-write clone of the whole file.
+    Kernel,
+    /// sendfile(2), on kernels without copy_file_range.
+    Sendfile,
+    /// read and write through a user-space buffer.
+    UserSpace,
 }
 
+impl Default for CopyMethod {
+    fn default() -> CopyMethod {
+        CopyMethod::Kernel
+    }
+}
 
-fn copy_parms(in_meta: &Metadata, out_meta: &Metadata) -> io::Result<(bool, bool)> {
-    let is_sparse = in_meta.st_blocks() < in_meta.st_size() / in_meta.st_blksize();
-    let is_xmount = in_meta.st_dev() != out_meta.st_dev();
-    Ok((is_sparse, is_xmount))
+/// Summary of a completed copy, as returned by `copy_stats()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CopyStats {
+    /// Bytes of the source handed to one of the copy methods. Any holes
+    /// in ranges given to the kernel count too, whether it writes them
+    /// out or preserves them, so this is not necessarily what was written
+    /// to disk. With `holes_skipped` it adds up to `bytes_total`.
+    pub bytes_copied: u64,
+    /// Length of the destination file, which the copy functions return.
+    pub bytes_total: u64,
+    /// Bytes of holes in a sparse source that were skipped.
+    pub holes_skipped: u64,
+    /// Number of contiguous ranges of data in the source.
+    pub data_segments: u64,
+    /// False if any of the data went through a user-space buffer.
+    pub used_kernel_copy: bool,
+    /// The method that transferred the most data, if fallbacks meant
+    /// more than one was used.
+    pub method: CopyMethod,
+    /// The syscalls made by the copy once both files were open, if
+    /// `CopyOptions::instrument` was set, and otherwise all zero.
+    pub syscalls: SyscallCounts,
 }
 
+/// Counts of the syscalls made by a copy, for tuning and testing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyscallCounts {
+    /// Calls to copy_file_range(2).
+    pub copy_file_range: u64,
+    /// Calls to sendfile(2).
+    pub sendfile: u64,
+    /// Calls to lseek(2), including those finding data and holes.
+    pub lseek: u64,
+    /// Reads into a user-space buffer.
+    pub read: u64,
+    /// Writes from a user-space buffer.
+    pub write: u64,
+    /// Calls to fstat(2) on either file.
+    pub fstat: u64,
+    /// Calls to mmap(2) mapping the source, for `use_mmap`.
+    pub mmap: u64,
+}
 
-pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
-    if !from.is_file() {
-        return Err(Error::new(ErrorKind::InvalidInput,
-                              "the source path is not an existing regular file"))
+impl SyscallCounts {
+    // The calls made since `before` was taken.
+    fn since(&self, before: &SyscallCounts) -> SyscallCounts {
+        SyscallCounts {
+            copy_file_range: self.copy_file_range.wrapping_sub(before.copy_file_range),
+            sendfile: self.sendfile.wrapping_sub(before.sendfile),
+            lseek: self.lseek.wrapping_sub(before.lseek),
+            read: self.read.wrapping_sub(before.read),
+            write: self.write.wrapping_sub(before.write),
+            fstat: self.fstat.wrapping_sub(before.fstat),
+            mmap: self.mmap.wrapping_sub(before.mmap),
+        }
     }
+}
 
-    let infd = File::open(from)?;
-    let outfd = File::create(to)?;
-    let in_meta = infd.metadata()?;
-    let out_meta = outfd.metadata()?;
+// Running totals for the calls made on this thread. They're cheap
+// enough to keep unconditionally, and a copy on one thread makes all
+// of its calls there, so it reports the difference.
+thread_local!(static SYSCALLS: Cell<SyscallCounts> = Cell::new(SyscallCounts::default()));
+
+fn count_syscall(bump: fn(&mut SyscallCounts)) {
+    SYSCALLS.with(|counts| {
+        let mut n = counts.get();
+        bump(&mut n);
+        counts.set(n);
+    });
+}
 
-    let (is_sparse, is_xmount) = copy_parms(&in_meta, &out_meta)?;
-    let uspace = is_xmount;
+fn fstat(fd: &File) -> io::Result<Metadata> {
+    count_syscall(|n| n.fstat += 1);
+    fd.metadata()
+}
 
-        let len = in_meta.len();
-    let total = if is_sparse {
-        copy_sparse(&infd, &outfd, uspace, len)?
+// Token bucket limiting the average rate of a copy. Up to a second's
+// worth of bytes may accumulate for a burst.
+struct Throttle {
+    rate: u64,
+    tokens: u64,
+    last: Instant,
+}
 
-    } else {
-        copy_range(&infd, &outfd, uspace, len)?
-    };
+impl Throttle {
+    fn new(rate: u64) -> Throttle {
+        Throttle {
+            rate: cmp::max(rate, 1),
+            tokens: 0,
+            last: Instant::now(),
+        }
+    }
 
-    outfd.set_permissions(in_meta.permissions())?;
-    Ok(total)
+    // Account for `n` bytes, sleeping until they fit within the rate.
+    fn consume(&mut self, n: u64) {
+        let now = Instant::now();
+        let elapsed = cmp::min(now - self.last, Duration::from_secs(1));
+        let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        let refill = (nanos as u128 * self.rate as u128 / 1_000_000_000) as u64;
+        self.tokens = cmp::min(self.tokens + refill, self.rate);
+        self.last = now;
+
+        if n <= self.tokens {
+            self.tokens -= n;
+        } else {
+            let deficit = (n - self.tokens) as u128;
+            self.tokens = 0;
+            let wait = (deficit * 1_000_000_000 / self.rate as u128) as u64;
+            thread::sleep(Duration::from_nanos(wait));
+            self.last = Instant::now();
+        }
+    }
 }
 
+// Mutable state of a single copy, threaded through the copy functions.
+struct CopyState<'a> {
+    stats: CopyStats,
+    throttle: Option<Throttle>,
+    // Called with the size of each chunk as it is copied.
+    progress: &'a mut dyn FnMut(u64),
+    // Set from another thread to stop the copy.
+    cancel: Option<&'a AtomicBool>,
+    // Bytes transferred by each CopyMethod, in declaration order.
+    by_method: [u64; 3],
+    // Fed all of the source's data, including holes, in order.
+    hasher: Option<&'a mut dyn Hasher>,
+    // copy_file_range has failed in a way specific to these files.
+    cfr_unusable: bool,
+    // As has sendfile.
+    sendfile_unusable: bool,
+    // Both files are on btrfs, so copy_sparse() may clone each segment.
+    clone_segments: bool,
+    deadline: Option<Instant>,
+    // Reused by every user-space copy made for this file.
+    buf: Vec<u8>,
+    // Where copy_range() expects the source and destination cursors to
+    // be before the next chunk, if it's tracking them.
+    resume_at: Option<(u64, u64)>,
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use iter;
-    use ffi::CStr;
-    use sys_common::io::test::{TempDir, tmpdir};
-    use fs::{read, OpenOptions};
-    use io::{Seek, SeekFrom, Write};
-    use path::PathBuf;
-
-    fn supported_kernel() -> bool {
-        let mut uname = unsafe { mem::zeroed() };
-        cvt(unsafe { libc::uname(&mut uname) }).unwrap();
+impl<'a> CopyState<'a> {
+    fn new(opts: &CopyOptions, progress: &'a mut dyn FnMut(u64)) -> CopyState<'a> {
+        CopyState {
+            stats: CopyStats {
+                used_kernel_copy: !opts.force_uspace,
+                ..CopyStats::default()
+            },
+            throttle: opts.max_bytes_per_sec.map(Throttle::new),
+            progress,
+            cancel: None,
+            by_method: [0; 3],
+            hasher: None,
+            cfr_unusable: false,
+            sendfile_unusable: false,
+            clone_segments: false,
+            deadline: opts.deadline,
+            buf: Vec::new(),
+            resume_at: None,
+        }
+    }
 
-        let release =
-            unsafe { CStr::from_ptr(uname.release.as_ptr()) }
-            .to_str().unwrap();
-        let s = release
-            .split(|c| c == '.' || c == '-')
-            .collect::<Vec<&str>>();
+    // Called between chunks to see whether the copy should stop.
+    fn check_stop(&self) -> io::Result<()> {
+        if let Some(cancel) = self.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(Error::new(ErrorKind::Interrupted, "the copy was cancelled"));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "the copy timed out"));
+            }
+        }
+        Ok(())
+    }
 
-        // Kernel >= 4.5
-        s[0].parse::<u32>().unwrap() >= 4 &&
-            s[1].parse::<u32>().unwrap() >= 5
+    // Move the cursors back to where the copy had got to, in case a
+    // failed kernel copy left them elsewhere.
+    fn resync(&self, reader: &File, writer: &File) -> io::Result<()> {
+        if let Some((in_pos, out_pos)) = self.resume_at {
+            lseek(reader, in_pos, Wence::Set)?;
+            lseek(writer, out_pos, Wence::Set)?;
+        }
+        Ok(())
     }
 
-    fn create_sparse(file: &PathBuf, len: u64) {
-        let fd = File::create(file).unwrap();
-        cvt(unsafe {libc::ftruncate64(fd.as_raw_fd(), len as i64)}).unwrap();
+    // Record `n` logical bytes as copied to the destination.
+    fn copied(&mut self, method: CopyMethod, n: u64) {
+        self.by_method[method as usize] += n;
+        self.stats.bytes_copied += n;
+        (self.progress)(n);
     }
 
-    fn create_sparse_with_data(file: &PathBuf, head: u64, tail: u64) -> u64 {
-        let data = "c00lc0d3";
-        let len = 4096u64 * 4096 + data.len() as u64 + tail;
+    // Record a hole of `n` bytes as skipped over.
+    fn skipped(&mut self, n: u64) {
+        if let Some(ref mut hasher) = self.hasher {
+            let zeroes = [0; ZERO_BLKSIZE];
+            let mut left = n;
+            while left > 0 {
+                let len = cmp::min(left, ZERO_BLKSIZE as u64);
+                hasher.write(&zeroes[..len as usize]);
+                left -= len;
+            }
+        }
+        self.stats.holes_skipped += n;
+        (self.progress)(n);
+    }
 
-        {
-            let fd = File::create(file).unwrap();
-            cvt(unsafe {libc::ftruncate64(fd.as_raw_fd(), len as i64)}).unwrap();
+    // The method that moved the most bytes, preferring the kernel
+    // methods on a tie. With no data, the method that would have been
+    // tried first.
+    fn method(&self) -> CopyMethod {
+        if self.stats.bytes_copied == 0 {
+            return if self.stats.used_kernel_copy {
+                CopyMethod::Kernel
+            } else {
+                CopyMethod::UserSpace
+            };
         }
+        let methods = [CopyMethod::Kernel, CopyMethod::Sendfile, CopyMethod::UserSpace];
+        let mut best = methods[0];
+        for &method in &methods[1..] {
+            if self.by_method[method as usize] > self.by_method[best as usize] {
+                best = method;
+            }
+        }
+        best
+    }
+}
 
-        let mut fd = OpenOptions::new()
-            .write(true)
-            .append(false)
-            .open(&file).unwrap();
+/// An error from a copy, with how far it got before failing.
+#[derive(Debug)]
+pub struct CopyError {
+    /// The underlying error.
+    pub error: io::Error,
+    /// Bytes of the destination completed before the failure, including
+    /// holes skipped in sparse files. A copy can resume from here.
+    pub bytes_copied: u64,
+}
 
-        fd.seek(SeekFrom::Start(head)).unwrap();
-        write!(fd, "{}", data).unwrap();
+impl CopyError {
+    /// The kind of the underlying error.
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+}
 
-        fd.seek(SeekFrom::Start(1024*4096)).unwrap();
-        write!(fd, "{}", data).unwrap();
+impl From<io::Error> for CopyError {
+    fn from(error: io::Error) -> CopyError {
+        CopyError { error, bytes_copied: 0 }
+    }
+}
 
-        fd.seek(SeekFrom::Start(4096*4096)).unwrap();
-        write!(fd, "{}", data).unwrap();
+impl From<CopyError> for io::Error {
+    fn from(err: CopyError) -> io::Error {
+        err.error
+    }
+}
 
-        len
+impl fmt::Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (after {} bytes)", self.error, self.bytes_copied)
     }
+}
 
+impl error::Error for CopyError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
 
-    fn tmps(dir: &TempDir) -> (PathBuf, PathBuf) {
-        let from = dir.path().join("from.bin");
-        let to = dir.path().join("to.bin");
-        (from, to)
+    fn cause(&self) -> Option<&dyn error::Error> {
+        Some(&self.error)
     }
+}
 
+/// An error from `copy_ctx()`, with the path it concerns.
+#[derive(Debug)]
+pub struct PathError {
+    /// The underlying error, and how far the copy got.
+    pub error: CopyError,
+    /// The file that couldn't be opened, created or examined, or `None`
+    /// if the error came from copying between the two once open.
+    pub path: Option<PathBuf>,
+}
 
-    fn is_sparse(fd: &File) -> io::Result<bool> {
-        let stat = fd.metadata()?;
-        Ok(stat.st_blocks() < stat.st_size() / stat.st_blksize())
+impl PathError {
+    fn at(path: &Path, error: io::Error) -> PathError {
+        PathError { error: error.into(), path: Some(path.to_path_buf()) }
     }
 
-    fn is_fsparse(file: &PathBuf) -> io::Result<bool> {
-        let fd = File::open(file)?;
-        is_sparse(&fd)
+    /// The kind of the underlying error.
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
     }
+}
 
-    #[test]
-    fn test_sparse_detection() {
-        assert!(!is_sparse(&File::open("Cargo.toml").unwrap()).unwrap());
+impl From<io::Error> for PathError {
+    fn from(error: io::Error) -> PathError {
+        PathError { error: error.into(), path: None }
+    }
+}
 
-        let dir = tmpdir();
-        let (from, _) = tmps(&dir);
-        create_sparse_with_data(&from, 0, 0);
+impl From<CopyError> for PathError {
+    fn from(error: CopyError) -> PathError {
+        PathError { error, path: None }
+    }
+}
 
-        {
-            let fd = File::open(&from).unwrap();
-            assert!(is_sparse(&fd).unwrap());
-        }
-        {
-            let mut fd = OpenOptions::new()
-                .write(true)
-                .append(true)
-                .open(&from).unwrap();
-            write!(fd, "{}", "test").unwrap();
-        }
-        {
-            let fd = File::open(&from).unwrap();
-            assert!(is_sparse(&fd).unwrap());
-        }
+impl From<PathError> for CopyError {
+    fn from(err: PathError) -> CopyError {
+        err.error
     }
+}
 
-    fn test_copy_range(uspace: bool) {
-        let dir = tmpdir();
-        let (from, to) = tmps(&dir);
-        let data = "test data";
+impl From<PathError> for io::Error {
+    fn from(err: PathError) -> io::Error {
+        err.error.error
+    }
+}
 
-        {
-            let mut fd = File::create(&to).unwrap();
-            write!(fd, "{}", data).unwrap();
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path {
+            Some(ref path) => write!(f, "{}: {}", path.display(), self.error.error),
+            None => self.error.fmt(f),
         }
+    }
+}
 
-        create_sparse_with_data(&from, 0, 0);
+impl error::Error for PathError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
 
-        {
-            let infd = File::open(&to).unwrap();
-            let outfd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&from).unwrap();
-            copy_range(&infd, &outfd, uspace, data.len() as u64).unwrap();
-        }
+    fn cause(&self) -> Option<&dyn error::Error> {
+        Some(&self.error.error)
+    }
+}
 
-        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+/// An error from `copy_tee()`, with which destination it came from.
+#[derive(Debug)]
+pub struct TeeError {
+    /// The underlying error.
+    pub error: io::Error,
+    /// The index in `dests` of the destination that failed to be
+    /// written, or `None` if the source failed.
+    pub index: Option<usize>,
+}
+
+impl TeeError {
+    /// The kind of the underlying error.
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
     }
+}
 
-    #[test]
-    fn test_copy_range_sparse_kernel() {
-        test_copy_range(false);
+impl From<io::Error> for TeeError {
+    fn from(error: io::Error) -> TeeError {
+        TeeError { error, index: None }
     }
+}
 
-    #[test]
-    fn test_copy_range_sparse_uspace() {
-        test_copy_range(true);
+impl From<TeeError> for io::Error {
+    fn from(err: TeeError) -> io::Error {
+        err.error
     }
+}
 
-    #[test]
-    fn test_sparse_copy_middle() {
-        if !supported_kernel() {
-            return;
+impl fmt::Display for TeeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "{} (writing destination {})", self.error, index),
+            None => write!(f, "{} (reading source)", self.error),
         }
+    }
+}
 
-        let dir = tmpdir();
-        let (from, to) = tmps(&dir);
-        let data = "test data";
+impl error::Error for TeeError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
 
-        {
-            let mut fd = File::create(&to).unwrap();
-            write!(fd, "{}", data).unwrap();
-        }
+    fn cause(&self) -> Option<&dyn error::Error> {
+        Some(&self.error)
+    }
+}
 
-        create_sparse(&from, 1024*1024);
+/// Make a single copy_file_range(2) call. Where an offset is `None` the
+/// descriptor's cursor is used and advanced, and where it's `Some` the
+/// copy is made at that offset and the cursor is left alone. Returns the
+/// number of bytes copied, which may be less than `len`, and is 0 once
+/// the source has ended.
+pub fn copy_file_range_safe(infd: &File, in_off: Option<u64>, outfd: &File,
+                            out_off: Option<u64>, len: usize) -> io::Result<u64> {
+    let mut in_pos = match in_off {
+        Some(off) => Some(Offset::new(off)?.raw()),
+        None => None,
+    };
+    let mut out_pos = match out_off {
+        Some(off) => Some(Offset::new(off)?.raw()),
+        None => None,
+    };
+    let in_ptr = in_pos.as_mut().map_or(ptr::null_mut(), |p| p as *mut libc::loff_t);
+    let out_ptr = out_pos.as_mut().map_or(ptr::null_mut(), |p| p as *mut libc::loff_t);
+
+    count_syscall(|n| n.copy_file_range += 1);
+    cvt_r(|| unsafe {
+        copy_file_range(infd.as_raw_fd(), in_ptr, outfd.as_raw_fd(), out_ptr, len, 0)
+    })
+    .map(|v| v as u64)
+}
+
+/// Copy `len` bytes from `in_off` in `infd` to `out_off` in `outfd`
+/// using copy_file_range(2). The descriptors' own cursors are left
+/// untouched. Returns the number of bytes copied, which is less than
+/// `len` only if the source ended first.
+pub fn copy_file_range_at(infd: &File, outfd: &File, in_off: u64, out_off: u64,
+                          len: u64) -> io::Result<u64> {
+    // Neither range may run past the largest offset.
+    Offset::new(in_off)?.add(len)?;
+    Offset::new(out_off)?.add(len)?;
+    let mut in_off = Offset::new(in_off)?.raw();
+    let mut out_off = Offset::new(out_off)?.raw();
+    let mut written = 0;
+    while written < len {
+        let nbytes = chunk_len(len - written);
+        let r = cvt_r(|| unsafe {
+            count_syscall(|n| n.copy_file_range += 1);
+            copy_file_range(infd.as_raw_fd(),
+                            &mut in_off,
+                            outfd.as_raw_fd(),
+                            &mut out_off,
+                            nbytes,
+                            0)
+        })?;
+        if r == 0 {
+            break;
+        }
+        written += r as u64;
+    }
+    Ok(written)
+}
+
+// As copy_file_range_at(), but reading and writing through a buffer
+// where copy_file_range can't be used between the files, or at all.
+fn copy_range_or_uspace_at(infd: &File, outfd: &File, in_off: u64, out_off: u64,
+                           len: u64) -> io::Result<u64> {
+    seed_copy_file_range();
+    let kernel = try_copy_syscall(&HAS_COPY_FILE_RANGE, &[libc::ENOSYS, libc::EPERM], || {
+        copy_file_range_at(infd, outfd, in_off, out_off, len)
+    });
+    match kernel {
+        Some(Err(ref err)) if err.raw_os_error()
+            .map_or(false, |errno| CFR_UNSUPPORTED_HERE.contains(&errno)) => {}
+        Some(result) => return result,
+        None => {}
+    }
+
+    // Whatever the kernel copied before failing is simply copied again.
+    let mut buf = vec![0; cmp::min(len, MIN_USPACE_BLKSIZE as u64) as usize];
+    let mut written = 0;
+    while written < len {
+        let next = cmp::min(len - written, buf.len() as u64) as usize;
+        count_syscall(|n| n.read += 1);
+        let n = read_full_at(infd, &mut buf[..next], in_off + written)?;
+        if n == 0 {
+            break;
+        }
+        count_syscall(|n| n.write += 1);
+        outfd.write_all_at(&buf[..n], out_off + written)?;
+        written += n as u64;
+    }
+    Ok(written)
+}
+
+// Size of the zero runs that punch_zeros will turn into holes, where
+// there's no destination to take it from.
+const ZERO_BLKSIZE: usize = 4 * 1024;
+
+// Write `buf` at the cursor, seeking over whole blocks of zeroes
+// rather than writing them so that they become holes. The caller is
+// responsible for extending the file over any trailing hole.
+fn write_punching_zeros(mut writer: &File, buf: &[u8], opts: &CopyOptions) -> io::Result<()> {
+    let granule = match opts.hole_granularity {
+        Some(g) => usize::try_from(cmp::max(g, 1)).map_err(|_| {
+            Error::new(ErrorKind::InvalidInput, "hole_granularity is too large")
+        })?,
+        None => ZERO_BLKSIZE,
+    };
+    // A block longer than `buf` is never found in it, so there's no need
+    // to look for one, and the offsets below can't overflow.
+    let granule = cmp::min(granule, buf.len() + 1);
+    let is_zero_block = |start: usize| {
+        let end = start + granule;
+        end <= buf.len() && buf[start..end].iter().all(|b| *b == 0)
+    };
+
+    let mut pos = 0;
+    while pos < buf.len() {
+        let zero = is_zero_block(pos);
+        let mut end = pos;
+        while end < buf.len() && is_zero_block(end) == zero {
+            end = cmp::min(end + granule, buf.len());
+        }
+
+        if !zero {
+            count_syscall(|n| n.write += 1);
+            writer.write_all(&buf[pos..end])?;
+        } else if opts.no_truncate {
+            // Stale data in the existing file must be removed.
+            let off = cursor(writer)?;
+            clear_range(writer, off, (end - pos) as u64)?;
+            lseek(writer, off + (end - pos) as u64, Wence::Set)?;
+        } else {
+            lseek(writer, (end - pos) as u64, Wence::Cur)?;
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+// A buffer of `blksize` bytes for copy_blocks() to read `reader` into.
+fn uspace_buffer<R: Read + ?Sized>(reader: &R, blksize: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(blksize);
+    unsafe {
+        buf.set_len(blksize);
+        reader.initializer().initialize(&mut buf);
+    }
+    buf
+}
+
+// Slightly modified version of io::copy() that only copies a set amount
+// of bytes, reading through `buf` and handing each block read to `sink`.
+fn copy_blocks<R, F>(reader: &mut R, nbytes: u64, buf: &mut [u8], allow_short: bool,
+                     mut sink: F) -> io::Result<u64>
+    where R: Read + ?Sized, F: FnMut(&[u8]) -> io::Result<()>
+{
+    let mut written = 0;
+    while written < nbytes {
+        let next = cmp::min(nbytes - written, buf.len() as u64) as usize;
+        count_syscall(|n| n.read += 1);
+        let len = match reader.read(&mut buf[..next]) {
+            Ok(0) if allow_short => break,
+            Ok(0) => return Err(Error::new(ErrorKind::InvalidData,
+                                           "Source file ended prematurely.")),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        sink(&buf[..len])?;
+        written += len as u64;
+    }
+    Ok(written)
+}
+
+/// Copies exactly `nbytes` bytes from `reader` to `writer` through a
+/// user-space buffer.
+///
+/// Unlike the file copies this works with any reader and writer, such as
+/// compressing or encrypting wrappers, but never uses the kernel fast
+/// paths. An error of kind `InvalidData` is returned if `reader` ends
+/// before `nbytes` bytes have been read.
+pub fn copy_stream<R, W>(reader: &mut R, writer: &mut W, nbytes: u64) -> io::Result<u64>
+    where R: Read + ?Sized, W: Write + ?Sized
+{
+    let mut buf = uspace_buffer(reader, MIN_USPACE_BLKSIZE);
+    copy_blocks(reader, nbytes, &mut buf, false, |buf| writer.write_all(buf))
+}
+
+/// Copy everything from `reader` into a new file at `to`, leaving holes
+/// where the stream has runs of zeroes, as `punch_zeros` does for files.
+/// A stream can't be asked where its holes are, so every byte is read
+/// and checked. Returns the length of the file written, including holes.
+pub fn copy_reader_sparse<R: Read + ?Sized>(reader: &mut R, to: &Path) -> io::Result<u64> {
+    let outfd = File::create(to)?;
+    let opts = CopyOptions {
+        punch_zeros: true,
+        hole_granularity: Some(outfd.metadata()?.st_blksize()),
+        ..CopyOptions::default()
+    };
+    let mut buf = uspace_buffer(reader, MIN_USPACE_BLKSIZE);
+    let len = copy_blocks(reader, u64::max_value(), &mut buf, true, |buf| {
+        write_punching_zeros(&outfd, buf, &opts)
+    })?;
+    allocate_file(&outfd, len)?;
+    Ok(len)
+}
+
+/// Sources shorter than this are read rather than mapped by `use_mmap`,
+/// as setting up and tearing down the mapping costs more than it saves.
+pub const MMAP_MIN_LEN: u64 = 1024 * 1024;
+
+// A read-only mapping of part of a file, unmapped on drop.
+struct SourceMap {
+    addr: *mut libc::c_void,
+    len: usize,
+    // Bytes from the start of the mapping, which has to be page-aligned,
+    // to the offset asked for.
+    skip: usize,
+}
+
+impl SourceMap {
+    // Map `len` bytes of `fd` from `off`, or None if the file can't be
+    // mapped.
+    fn new(fd: &File, off: u64, len: usize) -> io::Result<Option<SourceMap>> {
+        let skip = (off % os::page_size() as u64) as usize;
+        let start = Offset::new(off - skip as u64)?;
+        count_syscall(|n| n.mmap += 1);
+        let addr = unsafe {
+            libc::mmap64(ptr::null_mut(), skip + len, libc::PROT_READ, libc::MAP_PRIVATE,
+                         fd.as_raw_fd(), start.raw())
+        };
+        if addr == libc::MAP_FAILED {
+            let err = Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENODEV) | Some(libc::EACCES) | Some(libc::EINVAL) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        Ok(Some(SourceMap { addr, len: skip + len, skip }))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        let mapped = unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) };
+        &mapped[self.skip..]
+    }
+}
+
+impl Drop for SourceMap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+// For use_mmap, copy as much of the next `nbytes` from `reader` as lay
+// within the source when it was stat'd from a mapping of it, moving its
+// cursor on past them. Returns None where the source isn't mapped.
+fn copy_bytes_mmap(reader: &File, writer: &File, nbytes: usize, blksize: usize,
+                   opts: &CopyOptions, state: &mut CopyState) -> io::Result<Option<u64>> {
+    let meta = fstat(reader)?;
+    if !meta.file_type().is_file() || meta.len() < MMAP_MIN_LEN {
+        return Ok(None);
+    }
+    let off = cursor(reader)?;
+    // Past the stat'd length there's nothing to map, and touching the
+    // mapping there would fault.
+    let len = cmp::min(nbytes as u64, meta.len().saturating_sub(off)) as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    let map = match SourceMap::new(reader, off, len)? {
+        Some(map) => map,
+        None => return Ok(None),
+    };
+    for block in map.as_slice().chunks(blksize) {
+        write_block(writer, block, opts, state)?;
+    }
+    lseek(reader, off + len as u64, Wence::Set)?;
+    Ok(Some(len as u64))
+}
+
+fn copy_bytes_uspace(reader: &File, writer: &File, nbytes: usize, opts: &CopyOptions,
+                     state: &mut CopyState) -> io::Result<u64> {
+    let mut mapped = 0;
+    if opts.use_mmap {
+        let blksize = opts.block_size.unwrap_or(MIN_USPACE_BLKSIZE);
+        if let Some(n) = copy_bytes_mmap(reader, writer, nbytes, blksize, opts, state)? {
+            if n == nbytes as u64 {
+                return Ok(n);
+            }
+            // The source was shorter than asked for when it was mapped;
+            // reading the rest fails or stops short as it would have.
+            mapped = n;
+        }
+    }
+    // A sparse copy comes through here once for each data segment, so
+    // the buffer is kept for the rest of the copy rather than allocated
+    // every time.
+    let blksize = opts.block_size.unwrap_or(MIN_USPACE_BLKSIZE);
+    if state.buf.len() != blksize {
+        state.buf = uspace_buffer(reader, blksize);
+    }
+    let mut buf = mem::replace(&mut state.buf, Vec::new());
+    let result = copy_bytes_uspace_buf(reader, writer, nbytes - mapped as usize, &mut buf, opts,
+                                       state);
+    state.buf = buf;
+    result.map(|n| mapped + n)
+}
+
+// Hand one block read from the source on to `writer`, keeping the
+// hash, progress and throttle up to date.
+fn write_block(mut writer: &File, buf: &[u8], opts: &CopyOptions,
+               state: &mut CopyState) -> io::Result<()> {
+    state.check_stop()?;
+    if let Some(ref mut hasher) = state.hasher {
+        hasher.write(buf);
+    }
+    if opts.punch_zeros {
+        write_punching_zeros(writer, buf, opts)?;
+    } else {
+        count_syscall(|n| n.write += 1);
+        writer.write_all(buf)?;
+    }
+    state.copied(CopyMethod::UserSpace, buf.len() as u64);
+    if let Some(ref mut throttle) = state.throttle {
+        throttle.consume(buf.len() as u64);
+    }
+    Ok(())
+}
+
+// As copy_bytes_uspace(), but reading through the caller's `buf`, whose
+// length is then the block size.
+fn copy_bytes_uspace_buf(mut reader: &File, writer: &File, nbytes: usize, buf: &mut [u8],
+                         opts: &CopyOptions, state: &mut CopyState) -> io::Result<u64> {
+    copy_blocks(&mut reader, nbytes as u64, buf, opts.allow_short, |buf| {
+        write_block(writer, buf, opts, state)
+    })
+}
+
+
+// Set or clear O_DIRECT on an open file.
+fn set_direct(fd: &File, direct: bool) -> io::Result<()> {
+    let flags = cvt(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) })?;
+    let flags = if direct { flags | libc::O_DIRECT } else { flags & !libc::O_DIRECT };
+    cvt(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags) })?;
+    Ok(())
+}
+
+// The user-space copy for direct_io. With O_DIRECT the buffer, offsets
+// and lengths must all be multiples of `align`, or reads and writes fail
+// with EINVAL, so whole blocks are copied directly and whatever is left
+// over at the end goes through the page cache instead.
+fn copy_direct(infd: &File, outfd: &File, opts: &CopyOptions, len: u64, align: usize,
+               state: &mut CopyState) -> io::Result<u64> {
+    let blksize = opts.block_size.unwrap_or(MIN_USPACE_BLKSIZE);
+    let blksize = (blksize + align - 1) / align * align;
+    // Vec only guarantees the alignment of u8, so over-allocate and
+    // start at the first aligned byte.
+    let mut storage = vec![0; blksize + align];
+    let skip = (align - storage.as_ptr() as usize % align) % align;
+    let buf = &mut storage[skip..skip + blksize];
+
+    let body = len - len % align as u64;
+    let mut pos = 0;
+    while pos < len {
+        state.check_stop()?;
+        if pos == body {
+            set_direct(infd, false)?;
+            set_direct(outfd, false)?;
+        }
+        let next = if pos < body {
+            cmp::min(body - pos, blksize as u64) as usize
+        } else {
+            (len - pos) as usize
+        };
+        count_syscall(|n| n.read += 1);
+        let n = match infd.read_at(&mut buf[..next], pos) {
+            Ok(0) if opts.allow_short => break,
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof,
+                                           "Source file ended prematurely.")),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n % align != 0 && pos + (n as u64) < len {
+            // A short read mid-file leaves the next offset unaligned.
+            set_direct(infd, false)?;
+            set_direct(outfd, false)?;
+        }
+        if let Some(ref mut hasher) = state.hasher {
+            hasher.write(&buf[..n]);
+        }
+        count_syscall(|n| n.write += 1);
+        outfd.write_all_at(&buf[..n], pos)?;
+        state.copied(CopyMethod::UserSpace, n as u64);
+        if let Some(ref mut throttle) = state.throttle {
+            throttle.consume(n as u64);
+        }
+        pos += n as u64;
+    }
+    set_direct(infd, false)?;
+    set_direct(outfd, false)?;
+    lseek(outfd, pos, Wence::Set)?;
+    Ok(pos)
+}
+
+
+// Kernels prior to 4.5 don't have copy_file_range, and sendfile may
+// not support file-to-file copies (or be filtered), so we store the
+// availability in process-wide flags to avoid unnecessary syscalls.
+const SYSCALL_UNKNOWN: u8 = 0;
+const SYSCALL_AVAILABLE: u8 = 1;
+const SYSCALL_UNAVAILABLE: u8 = 2;
+static HAS_COPY_FILE_RANGE: AtomicU8 = AtomicU8::new(SYSCALL_UNKNOWN);
+static HAS_SENDFILE: AtomicU8 = AtomicU8::new(SYSCALL_UNKNOWN);
+
+// Set to 1 on kernels known to lack copy_file_range to skip probing it.
+const NO_CFR_ENV: &str = "RUST_FS_NO_CFR";
+
+// Mark `flag` unavailable ahead of its first probe if `value`, that of
+// NO_CFR_ENV, asks.
+fn seed_from_env(flag: &AtomicU8, value: Option<&str>) {
+    if flag.load(Ordering::Relaxed) == SYSCALL_UNKNOWN && value == Some("1") {
+        let _ = flag.compare_exchange(SYSCALL_UNKNOWN, SYSCALL_UNAVAILABLE,
+                                      Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+// Mark `flag` unavailable ahead of its first probe if the kernel is too
+// old to have copy_file_range, rather than make a call bound to fail.
+fn seed_from_kernel(flag: &AtomicU8) {
+    if flag.load(Ordering::Relaxed) == SYSCALL_UNKNOWN && !kernel_supports_copy_file_range() {
+        let _ = flag.compare_exchange(SYSCALL_UNKNOWN, SYSCALL_UNAVAILABLE,
+                                      Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+// Seed HAS_COPY_FILE_RANGE from the environment and the kernel release
+// before its first use. Both are only looked at the once, so changing the
+// environment later in the process has no effect.
+fn seed_copy_file_range() {
+    static SEEDED: Once = Once::new();
+    SEEDED.call_once(|| {
+        let value = env::var(NO_CFR_ENV).ok();
+        seed_from_env(&HAS_COPY_FILE_RANGE, value.as_ref().map(|v| &v[..]));
+        seed_from_kernel(&HAS_COPY_FILE_RANGE);
+    });
+}
+
+/// Whether the running kernel is new enough to have copy_file_range(2),
+/// i.e. 4.5 or later, going by the release uname(2) gives. A release
+/// that can't be parsed is assumed to be new enough. Distributions
+/// backport syscalls and seccomp filters block them, so the copies
+/// still fall back if the call itself fails with ENOSYS.
+pub fn kernel_supports_copy_file_range() -> bool {
+    let mut uname: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uname) } == -1 {
+        return true;
+    }
+    let release = unsafe { CStr::from_ptr(uname.release.as_ptr()) };
+    release_supports_copy_file_range(&release.to_string_lossy())
+}
+
+fn release_supports_copy_file_range(release: &str) -> bool {
+    parse_kernel_version(release).map_or(true, |version| version >= (4, 5))
+}
+
+// The (major, minor) version at the start of a kernel release such as
+// "5.15.0-generic".
+fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+// Attempt a copy with `copy`, recording in `flag` whether the syscall
+// is usable. Any of the `unsupported` errnos mark it unavailable, in
+// which case None is returned and the caller should try the next tier.
+fn try_copy_syscall<F>(flag: &AtomicU8, unsupported: &[i32],
+                       copy: F) -> Option<io::Result<u64>>
+    where F: FnOnce() -> io::Result<u64>
+{
+    if flag.load(Ordering::Relaxed) == SYSCALL_UNAVAILABLE {
+        return None;
+    }
+
+    let result = copy();
+    let state = match result {
+        Err(ref err) => match err.raw_os_error() {
+            Some(errno) if unsupported.contains(&errno) => SYSCALL_UNAVAILABLE,
+            _ => return Some(result),
+        },
+        Ok(_) => SYSCALL_AVAILABLE,
+    };
+
+    // Only the first probe on any thread records the result.
+    let _ = flag.compare_exchange(SYSCALL_UNKNOWN, state,
+                                  Ordering::Relaxed, Ordering::Relaxed);
+    if state == SYSCALL_AVAILABLE {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+// Errors from copy_file_range meaning it can't be used between the
+// files at hand, though it may work for others. Some FUSE and network
+// filesystems fail with EINVAL or EOPNOTSUPP, kernels before 5.3 with
+// EXDEV between filesystems, and an O_APPEND destination with EBADF.
+const CFR_UNSUPPORTED_HERE: &[i32] = &[
+    libc::EINVAL, libc::EOPNOTSUPP, libc::EXDEV, libc::EBADF,
+];
+
+// Likewise for sendfile. Before 2.6.33 it only wrote to sockets, and it
+// still fails with EINVAL for an O_APPEND destination or a source that
+// can't be spliced from, and EPERM where a seccomp filter forbids it.
+const SENDFILE_UNSUPPORTED_HERE: &[i32] = &[libc::EINVAL, libc::EPERM];
+
+// Make a single attempt at copying up to nbytes, by the first method
+// that works, and return however much that managed. It's up to the
+// caller to loop until everything is across.
+fn copy_bytes_once(reader: &File, writer: &File, opts: &CopyOptions, nbytes: u64,
+                   state: &mut CopyState) -> io::Result<u64> {
+    let nbytes = chunk_len(nbytes);
+    if !opts.force_uspace && nbytes as u64 >= opts.kernel_copy_min_bytes {
+        seed_copy_file_range();
+        // Only put the cursors back if a failed call may have moved them.
+        let mut attempted = false;
+        let mut try_sendfile = true;
+        if !state.cfr_unusable {
+            let kernel = try_copy_syscall(
+                &HAS_COPY_FILE_RANGE, &[libc::ENOSYS, libc::EPERM],
+                || {
+                    attempted = true;
+                    copy_bytes_kernel(reader, writer, nbytes, opts.copy_flags)
+                });
+            match kernel {
+                // Flags this kernel doesn't know; ignore them in user-space.
+                Some(Err(ref err)) if opts.copy_flags != 0 &&
+                    err.raw_os_error() == Some(libc::EINVAL) => try_sendfile = false,
+                // Give up on it for the rest of this copy only, as it's the
+                // first failure that tells us.
+                Some(Err(ref err)) if err.raw_os_error()
+                    .map_or(false, |errno| CFR_UNSUPPORTED_HERE.contains(&errno)) => {
+                    state.cfr_unusable = true;
+                }
+                Some(result) => {
+                    return result.map(|n| { state.copied(CopyMethod::Kernel, n); n });
+                }
+                None => {}
+            }
+        }
+
+        if try_sendfile && !state.sendfile_unusable {
+            if attempted {
+                state.resync(reader, writer)?;
+                attempted = false;
+            }
+            let sendfile = try_copy_syscall(&HAS_SENDFILE, &[libc::ENOSYS], || {
+                attempted = true;
+                copy_bytes_sendfile(reader, writer, nbytes)
+            });
+            match sendfile {
+                // Only missing altogether is it given up on for good.
+                Some(Err(ref err)) if err.raw_os_error()
+                    .map_or(false, |errno| SENDFILE_UNSUPPORTED_HERE.contains(&errno)) => {
+                    state.sendfile_unusable = true;
+                }
+                Some(result) => {
+                    return result.map(|n| { state.copied(CopyMethod::Sendfile, n); n });
+                }
+                None => {}
+            }
+        }
+        // Otherwise fall back to user-space.
+        if attempted {
+            state.resync(reader, writer)?;
+        }
+    }
+
+    state.stats.used_kernel_copy = false;
+    copy_bytes_uspace(reader, writer, nbytes, opts, state)
+}
+
+
+/// Copy len bytes from whereever the descriptor cursors are set. The
+/// count is of logical bytes: a kernel copy over a hole in the source
+/// reports it as copied, though it may have written nothing.
+fn copy_range(infd: &File, outfd: &File, opts: &CopyOptions, len: u64,
+              state: &mut CopyState) -> io::Result<u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+
+    // The kernel and user-space copies all use and advance the cursors,
+    // so switching between them mid-copy would carry on from the same
+    // place. But rather than rely on a failed kernel copy never having
+    // moved them, note where each chunk should start.
+    let start = if opts.force_uspace {
+        None
+    } else {
+        Some((cursor(infd)?, cursor(outfd)?))
+    };
+
+    let mut written = 0;
+    while written < len {
+        state.check_stop()?;
+        state.resume_at = start.map(|(in_pos, out_pos)| (in_pos + written, out_pos + written));
+        let result = copy_bytes_once(&infd, &outfd, opts, len - written, state)?;
+        // The kernel copies return 0 at EOF rather than failing, so a
+        // source truncated underneath us would otherwise spin forever.
+        if result == 0 {
+            if opts.allow_short {
+                break;
+            }
+            return Err(Error::new(ErrorKind::UnexpectedEof,
+                                  "Source file ended prematurely."));
+        }
+        written += result;
+    }
+    state.resume_at = None;
+    Ok(written)
+}
+
+/// Attempt a copy-on-write clone of the whole file via the FICLONE
+/// ioctl. Returns `Ok(false)` if the filesystem doesn't support it.
+fn try_reflink(infd: &File, outfd: &File) -> io::Result<bool> {
+    let r = unsafe {
+        libc::ioctl(outfd.as_raw_fd(), FICLONE as _, infd.as_raw_fd())
+    };
+
+    if r == -1 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // Kernels prior to 4.5 don't know FICLONE and return ENOTTY.
+            Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) |
+            Some(libc::EXDEV) | Some(libc::ENOTTY) => Ok(false),
+            _ => Err(err)
+        }
+    } else {
+        Ok(true)
+    }
+}
+
+/// Clone `len` bytes at `in_off` in `infd` to `out_off` in `outfd`,
+/// sharing their extents via the BTRFS_IOC_CLONE_RANGE ioctl. Offsets
+/// and length must be multiples of the block size, except that the range
+/// may end at the end of the source. Returns `Ok(false)` if the range
+/// can't be cloned. The descriptors' cursors are left untouched.
+fn try_clone_range(infd: &File, in_off: u64, len: u64, outfd: &File,
+                   out_off: u64) -> io::Result<bool> {
+    let args = BtrfsCloneRangeArgs {
+        src_fd: infd.as_raw_fd() as i64,
+        src_offset: Offset::new(in_off)?.get(),
+        src_length: len,
+        dest_offset: Offset::new(out_off)?.get(),
+    };
+    let r = unsafe {
+        libc::ioctl(outfd.as_raw_fd(), BTRFS_IOC_CLONE_RANGE as _, &args)
+    };
+
+    if r == -1 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) |
+            Some(libc::EXDEV) | Some(libc::ENOTTY) => Ok(false),
+            _ => Err(err)
+        }
+    } else {
+        Ok(true)
+    }
+}
+
+fn on_btrfs(fd: &File) -> io::Result<bool> {
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    cvt(unsafe { libc::fstatfs(fd.as_raw_fd(), &mut buf) })?;
+    Ok(buf.f_type as u32 == BTRFS_SUPER_MAGIC)
+}
+
+// The caller's `len` stands in for the end of the file, so that reaching
+// it costs neither a stat nor a second seek.
+fn next_sparse_segments(fd: &File, pos: u64, len: u64) -> io::Result<(u64, u64)> {
+    let next_data = match lseek(fd, pos, Wence::Data)? {
+        SeekOff::Offset(off) => off,
+        SeekOff::EOF => return Ok((len, len)),
+    };
+    let next_hole = match lseek(fd, next_data, Wence::Hole)? {
+        SeekOff::Offset(off) => off,
+        SeekOff::EOF => len
+    };
+
+    Ok((next_data, next_hole))
+}
+
+/// Whether the file at `path` is sparse, i.e. has fewer bytes allocated
+/// on disk than its length. This is the same test `copy()` uses to decide
+/// whether to look for holes. Symlinks are followed.
+pub fn is_sparse(path: &Path) -> io::Result<bool> {
+    Ok(meta_is_sparse(&fs::metadata(path)?))
+}
+
+/// Return the `(data_start, data_end)` ranges holding data in `fd`,
+/// as reported by the filesystem. Holes are the gaps between them.
+/// Leaves the cursor at an unspecified position.
+pub fn sparse_segments(fd: &File) -> io::Result<Vec<(u64, u64)>> {
+    let len = fd.metadata()?.len();
+    let mut segments = Vec::new();
+
+    let mut pos = 0;
+    while pos < len {
+        let (next_data, next_hole) = next_sparse_segments(fd, pos, len)?;
+        if next_data >= len {
+            break;
+        }
+        segments.push((next_data, next_hole));
+        pos = next_hole;
+    }
+
+    Ok(segments)
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+/// An extent of a file, as mapped by `fiemap()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    /// Offset of the extent within the file.
+    pub logical: u64,
+    /// Offset of the extent on the device, where the filesystem has one.
+    pub physical: u64,
+    /// Length of the extent in bytes.
+    pub length: u64,
+    /// Allocated but never written, so it reads as zeroes.
+    pub unwritten: bool,
+    /// Shared with another file, e.g. by a reflink.
+    pub shared: bool,
+}
+
+/// Return the extents of `fd` in file order, via the FS_IOC_FIEMAP
+/// ioctl. Unlike `sparse_segments()` this tells preallocated extents
+/// that were never written apart from data, and shows which extents
+/// are shared with other files. Any dirty data is flushed first so
+/// that it has been mapped. Fails with ENOTTY or EOPNOTSUPP where the
+/// filesystem can't map extents.
+pub fn fiemap(fd: &File) -> io::Result<Vec<Extent>> {
+    let mut extents = Vec::new();
+    let mut start = 0;
+
+    loop {
+        let mut map: Fiemap = unsafe { mem::zeroed() };
+        map.fm_start = start;
+        map.fm_length = u64::max_value() - start;
+        map.fm_flags = FIEMAP_FLAG_SYNC;
+        map.fm_extent_count = FIEMAP_BATCH as u32;
+        cvt(unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) })?;
+
+        let mapped = &map.fm_extents[..map.fm_mapped_extents as usize];
+        extents.extend(mapped.iter().map(|ext| Extent {
+            logical: ext.fe_logical,
+            physical: ext.fe_physical,
+            length: ext.fe_length,
+            unwritten: ext.fe_flags & FIEMAP_EXTENT_UNWRITTEN != 0,
+            shared: ext.fe_flags & FIEMAP_EXTENT_SHARED != 0,
+        }));
+        match mapped.last() {
+            Some(ext) if ext.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = ext.fe_logical + ext.fe_length;
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+// As next_sparse_segments(), but from an extent map. Unwritten extents
+// read as zeroes, so they're holes as far as a copy is concerned, and
+// extents that follow on from each other are one segment.
+fn next_mapped_segments(extents: &[Extent], pos: u64, len: u64) -> (u64, u64) {
+    // The first extent ending after `pos`.
+    let first = match extents.binary_search_by(|ext| {
+        if ext.logical + ext.length <= pos { cmp::Ordering::Less } else { cmp::Ordering::Greater }
+    }) {
+        Ok(i) | Err(i) => i,
+    };
+
+    let mut data = extents[first..].iter().filter(|ext| !ext.unwritten);
+    let (next_data, mut next_hole) = match data.next() {
+        Some(ext) => (cmp::max(ext.logical, pos), ext.logical + ext.length),
+        None => return (len, len),
+    };
+    for ext in data {
+        if ext.logical > next_hole {
+            break;
+        }
+        next_hole = ext.logical + ext.length;
+    }
+
+    if next_data >= len {
+        (len, len)
+    } else {
+        // The last extent is rounded up to a whole block.
+        (next_data, cmp::min(next_hole, len))
+    }
+}
+
+fn copy_sparse(infd: &File, outfd: &File, opts: &CopyOptions, len: u64,
+               state: &mut CopyState) -> io::Result<u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+
+    // Holes over the existing contents of the destination must be cleared,
+    // and anything beyond the copy is left for the caller to trim.
+    let out_meta = fstat(outfd)?;
+    let stale_len = if opts.no_truncate { out_meta.len() } else { 0 };
+    let set_len = if opts.no_truncate { ensure_len } else { allocate_file };
+    if !opts.presized {
+        set_len(&outfd, len)?;
+    }
+    let min_hole = opts.min_hole_len.unwrap_or(out_meta.st_blksize());
+
+    let map = if opts.use_fiemap && !opts.follow_growth {
+        match fiemap(infd) {
+            Ok(extents) => Some(extents),
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOTTY) ||
+                err.raw_os_error() == Some(libc::EOPNOTSUPP) => None,
+            Err(err) => return Err(err),
+        }
+    } else {
+        None
+    };
+    // On btrfs each data segment can be cloned rather than copied, at
+    // least until a clone fails. The whole file is only cloned at once if
+    // the destination can be replaced outright, unlike with `presized`.
+    let mut clone = state.clone_segments;
+
+    let find_segments = |pos, len| match map {
+        Some(ref extents) => Ok(next_mapped_segments(extents, pos, len)),
+        None => next_sparse_segments(infd, pos, len),
+    };
+
+    // The source may be changing size as we copy it. Unless following
+    // that, everything is clamped to the original `len`, so that data
+    // appended is dropped and a truncated tail becomes a hole. Either
+    // way a source truncated in the middle of a data segment fails the
+    // copy, as that data can no longer be read.
+    let mut len = len;
+    let mut pos = 0;
+    // The segment found while looking past the last one, if any.
+    let mut peeked = None;
+
+    loop {
+        if pos >= len {
+            if !opts.follow_growth {
+                break;
+            }
+            let cur = fstat(infd)?.len();
+            if cur <= len {
+                break;
+            }
+            len = cur;
+        }
+
+        state.check_stop()?;
+        let (next_data, next_hole) = match peeked.take() {
+            Some(segment) => segment,
+            None => find_segments(pos, len)?,
+        };
+
+        // No more data before `len` may mean the source has shrunk.
+        if next_data >= len && opts.follow_growth {
+            len = cmp::max(pos, cmp::min(len, fstat(infd)?.len()));
+        }
+        let (mut next_data, mut next_hole) =
+            (cmp::min(next_data, len), cmp::min(next_hole, len));
+
+        // Two seeks aren't worth it to skip a short hole, so join the
+        // segments either side and copy it as zeroes. Only the hole at the
+        // start of the file can be before a segment, as the rest were
+        // joined on while looking past the one before.
+        if next_data < len && next_data - pos < min_hole {
+            next_data = pos;
+        }
+        while next_hole > next_data && next_hole < len {
+            let (data, hole) = find_segments(next_hole, len)?;
+            if data >= len {
+                if len - next_hole < min_hole {
+                    next_hole = len;
+                }
+                break;
+            }
+            if data - next_hole >= min_hole {
+                peeked = Some((data, hole));
+                break;
+            }
+            next_hole = cmp::min(hole, len);
+        }
+
+        lseek(infd, next_data, Wence::Set)?;
+        lseek(outfd, next_data, Wence::Set)?;
+
+        // Skipped holes count towards progress so that it reaches len.
+        if next_data > pos {
+            if pos < stale_len && !opts.presized {
+                clear_range(outfd, pos, cmp::min(next_data, stale_len) - pos)?;
+                lseek(outfd, next_data, Wence::Set)?;
+            }
+            state.skipped(next_data - pos);
+        }
+
+        if next_hole > next_data {
+            state.stats.data_segments += 1;
+        }
+        if clone && next_hole > next_data {
+            clone = try_clone_range(infd, next_data, next_hole - next_data, outfd, next_data)?;
+            if clone {
+                state.copied(CopyMethod::Kernel, next_hole - next_data);
+                pos = next_hole;
+                continue;
+            }
+        }
+        let _written = copy_range(infd, outfd, opts, next_hole - next_data, state)?;
+        pos = next_hole;
+    }
+
+    // Nothing is written over a trailing hole, so make sure the length
+    // doesn't depend on the sizing above.
+    if !opts.presized {
+        set_len(&outfd, len)?;
+    }
+
+    Ok(len)
+}
+
+
+fn copy_times(in_meta: &Metadata, outfd: &File) -> io::Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: in_meta.st_atime() as libc::time_t,
+            tv_nsec: in_meta.st_atime_nsec() as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: in_meta.st_mtime() as libc::time_t,
+            tv_nsec: in_meta.st_mtime_nsec() as libc::c_long,
+        },
+    ];
+    cvt(unsafe { libc::futimens(outfd.as_raw_fd(), times.as_ptr()) })?;
+    Ok(())
+}
+
+/// Apply the source's owner and group to the destination. Only
+/// privileged processes may do this, so EPERM is ignored.
+fn copy_ownership(in_meta: &Metadata, outfd: &File) -> io::Result<()> {
+    let r = unsafe {
+        libc::fchown(outfd.as_raw_fd(), in_meta.st_uid(), in_meta.st_gid())
+    };
+    if r == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EPERM) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+// The xattrs holding a file's POSIX ACLs. Only directories have a
+// default ACL.
+const ACL_XATTRS: &[&[u8]] = &[b"system.posix_acl_access\0", b"system.posix_acl_default\0"];
+
+/// Copy the access and default ACLs of `infd` to `outfd`, where the
+/// source has them and the destination's filesystem can hold them. Must
+/// come after the mode is set, or the chmod will undo part of the access
+/// ACL.
+fn copy_acls(infd: &File, outfd: &File) -> io::Result<()> {
+    for name in ACL_XATTRS {
+        let cname = name.as_ptr() as *const libc::c_char;
+        let value = match xattr_read(|buf, size| unsafe {
+            libc::fgetxattr(infd.as_raw_fd(), cname, buf, size)
+        }) {
+            Ok(value) => value,
+            // No ACL, or none possible on the source's filesystem.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) ||
+                e.raw_os_error() == Some(libc::ENOTSUP) => continue,
+            Err(e) => return Err(e),
+        };
+        let r = cvt(unsafe {
+            libc::fsetxattr(outfd.as_raw_fd(), cname,
+                            value.as_ptr() as *const libc::c_void, value.len(), 0)
+        });
+        match r {
+            // None possible on the destination's filesystem either, which
+            // leaves the mode bits as the nearest it can get.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn get_inode_flags(fd: &File) -> io::Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    cvt(unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) })?;
+    Ok(flags)
+}
+
+fn set_inode_flags(fd: &File, flags: libc::c_int) -> io::Result<()> {
+    cvt(unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) })?;
+    Ok(())
+}
+
+// Errors meaning a filesystem has no inode flags, or not the ones asked.
+fn flags_unsupported(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => true,
+        _ => false,
+    }
+}
+
+/// Give `outfd` the chattr(1) flags of `infd`, clearing any others it
+/// has. If the destination rejects the whole set, each flag is tried in
+/// turn and the ones it doesn't support are left off.
+fn copy_inode_flags(infd: &File, outfd: &File) -> io::Result<()> {
+    let flags = match get_inode_flags(infd) {
+        Ok(flags) => flags & FS_CHATTR_FL,
+        Err(ref e) if flags_unsupported(e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let base = match get_inode_flags(outfd) {
+        Ok(out_flags) => out_flags & !FS_CHATTR_FL,
+        Err(ref e) if flags_unsupported(e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    match set_inode_flags(outfd, base | flags) {
+        Err(ref e) if flags_unsupported(e) => {}
+        result => return result,
+    }
+    let mut accepted = base;
+    for bit in (0..32).map(|i| 1 << i).filter(|bit| flags & bit != 0) {
+        match set_inode_flags(outfd, accepted | bit) {
+            Ok(()) => accepted |= bit,
+            Err(ref e) if flags_unsupported(e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Run an xattr query that fills a buffer, first asking for the
+// required size. Retries if the value grows between the two calls,
+// including when it was empty, as a second call with a size of 0 is just
+// another query and returns the new length without filling anything.
+fn xattr_read<F>(mut f: F) -> io::Result<Vec<u8>>
+    where F: FnMut(*mut libc::c_void, libc::size_t) -> libc::ssize_t
+{
+    loop {
+        let size = cvt(f(ptr::null_mut(), 0))? as usize;
+        let mut buf: Vec<u8> = Vec::with_capacity(size);
+        match cvt(f(buf.as_mut_ptr() as *mut libc::c_void, size)) {
+            Ok(len) if len as usize > size => continue,
+            Ok(len) => {
+                unsafe { buf.set_len(len as usize) };
+                return Ok(buf);
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copy all extended attributes from `infd` to `outfd`. Attributes the
+/// destination rejects (e.g. an unsupported namespace) are skipped.
+fn copy_xattrs(infd: &File, outfd: &File) -> io::Result<()> {
+    let names = match xattr_read(|buf, size| unsafe {
+        libc::flistxattr(infd.as_raw_fd(), buf as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    // The list is a sequence of NUL-terminated names.
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let mut cname = name.to_vec();
+        cname.push(0);
+        let cname = cname.as_ptr() as *const libc::c_char;
+
+        let value = match xattr_read(|buf, size| unsafe {
+            libc::fgetxattr(infd.as_raw_fd(), cname, buf, size)
+        }) {
+            Ok(value) => value,
+            // Removed since the names were listed.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(e) => return Err(e),
+        };
+        let r = unsafe {
+            libc::fsetxattr(outfd.as_raw_fd(), cname,
+                            value.as_ptr() as *const libc::c_void, value.len(), 0)
+        };
+        if r == -1 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ENOTSUP) | Some(libc::EPERM) => {}
+                _ => return Err(err),
+            }
+        }
+    }
+    Ok(())
+}
+
+// The directory `path` is in, which for a bare file name is the current
+// directory rather than the empty path.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    }
+}
+
+// Failing to create `path` with ENOENT most likely means its directory
+// is missing, which the bare error doesn't say.
+fn explain_missing_parent(path: &Path, err: io::Error) -> io::Error {
+    let parent = parent_dir(path);
+    if err.kind() == ErrorKind::NotFound && fs::metadata(parent).is_err() {
+        Error::new(ErrorKind::NotFound,
+                   format!("the destination directory {} does not exist", parent.display()))
+    } else {
+        err
+    }
+}
+
+// Rejecting a source that isn't a regular file, saying what it is
+// instead.
+fn not_regular_file(meta: &Metadata) -> Error {
+    let what = match meta.st_mode() & libc::S_IFMT {
+        libc::S_IFDIR => "a directory",
+        libc::S_IFLNK => "a symbolic link",
+        libc::S_IFIFO => "a FIFO",
+        libc::S_IFSOCK => "a socket",
+        libc::S_IFBLK => "a block device",
+        libc::S_IFCHR => "a character device",
+        _ => "not a regular file",
+    };
+    Error::new(ErrorKind::InvalidInput, format!("the source is {}", what))
+}
+
+// Fail unless `from` is, or links to, a regular file.
+fn check_source_is_file(from: &Path) -> io::Result<()> {
+    match fs::metadata(from) {
+        Ok(ref meta) if meta.is_file() => Ok(()),
+        Ok(ref meta) => Err(not_regular_file(meta)),
+        Err(_) => Err(Error::new(ErrorKind::InvalidInput,
+                                 "the source path is not an existing regular file")),
+    }
+}
+
+/// Flush the directory entry of a newly created file.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    File::open(parent_dir(path))?.sync_all()
+}
+
+// Fill `buf` from `off` as far as possible, returning less only at EOF.
+fn read_full_at(fd: &File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match fd.read_at(&mut buf[filled..], off + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Compare the contents of two files a block at a time, without moving
+/// their cursors. Holes read back as zeroes so sparse and dense copies
+/// compare equal.
+fn verify_copy(infd: &File, outfd: &File, blksize: usize) -> io::Result<()> {
+    let mut inbuf = vec![0; blksize];
+    let mut outbuf = vec![0; blksize];
+
+    let mut off = 0;
+    loop {
+        let inlen = read_full_at(infd, &mut inbuf, off)?;
+        let outlen = read_full_at(outfd, &mut outbuf, off)?;
+        if inbuf[..inlen] != outbuf[..outlen] {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  "the destination does not match the source"))
+        }
+        if inlen < blksize {
+            return Ok(());
+        }
+        off += blksize as u64;
+    }
+}
+
+// Whether fewer bytes are allocated on disk than the file's length.
+// st_blocks is always in 512-byte units, whatever st_blksize says.
+fn meta_is_sparse(meta: &Metadata) -> bool {
+    meta.st_size() > 0 && meta.st_blocks().saturating_mul(512) < meta.st_size()
+}
+
+// Extending a file with ftruncate leaves a hole on filesystems that
+// support them, while others (e.g. FAT) allocate and zero the new range.
+const HOLE_PROBE_LEN: u64 = 1024 * 1024;
+
+// A filesystem's answer doesn't change while it's mounted, so each is
+// probed once, by st_dev, rather than growing every destination on it.
+static HOLES_LOCK: Mutex = Mutex::new();
+static mut HOLES_BY_DEV: Option<HashMap<u64, bool>> = None;
+
+fn supports_holes(fd: &File) -> io::Result<bool> {
+    let meta = fstat(fd)?;
+    unsafe {
+        let _guard = HOLES_LOCK.lock();
+        if let Some(&holes) = HOLES_BY_DEV.as_ref().and_then(|m| m.get(&meta.st_dev())) {
+            return Ok(holes);
+        }
+    }
+    let holes = probe_holes(fd)?;
+    unsafe {
+        let _guard = HOLES_LOCK.lock();
+        HOLES_BY_DEV.get_or_insert_with(HashMap::new).insert(meta.st_dev(), holes);
+    }
+    Ok(holes)
+}
+
+fn probe_holes(fd: &File) -> io::Result<bool> {
+    let before = fstat(fd)?;
+    allocate_file(fd, before.st_size() + HOLE_PROBE_LEN)?;
+    let after = fstat(fd);
+    allocate_file(fd, before.st_size())?;
+    let grown = after?.st_blocks().saturating_sub(before.st_blocks());
+    Ok(grown.saturating_mul(512) < HOLE_PROBE_LEN)
+}
+
+/// Whether the files are on different filesystems, judged by their
+/// st_dev alone. This is only a heuristic: bind mounts of one filesystem
+/// share an st_dev, yet the kernel still refuses to clone across them,
+/// and btrfs gives each subvolume its own st_dev even though extents can
+/// be shared between them. Where it matters, an EXDEV from the clone or
+/// copy itself is the real answer, and try_reflink() treats it as such.
+fn is_xmount(in_meta: &Metadata, out_meta: &Metadata) -> bool {
+    in_meta.st_dev() != out_meta.st_dev()
+}
+
+/// Resolve the caller's options against the files being copied.
+fn copy_parms(in_meta: &Metadata, out_meta: &Metadata,
+              opts: &CopyOptions) -> io::Result<CopyOptions> {
+    let is_sparse = meta_is_sparse(in_meta);
+    // Files on different mounts can't share extents, so FICLONE fails
+    // with EXDEV, as does copy_file_range on older kernels. Going through
+    // user-space avoids trying either, unless asked to try anyway.
+    let xmount = is_xmount(in_meta, out_meta);
+    Ok(CopyOptions {
+        force_uspace: opts.force_uspace || (xmount && !opts.allow_xmount_kernel_copy) ||
+            opts.punch_zeros || opts.max_bytes_per_sec.is_some() || opts.direct_io ||
+            opts.materialize_holes,
+        preserve_sparse: opts.preserve_sparse && !opts.materialize_holes &&
+            (is_sparse || opts.force_sparse || opts.use_fiemap),
+        punch_zeros: opts.punch_zeros && !opts.materialize_holes,
+        block_size: opts.block_size.or_else(|| {
+            Some(cmp::max(out_meta.st_blksize() as usize, MIN_USPACE_BLKSIZE))
+        }),
+        hole_granularity: opts.hole_granularity.or(Some(out_meta.st_blksize())),
+        ..opts.clone()
+    })
+}
+
+
+/// Copies files one after another with the same options, keeping what
+/// can be reused between them. The user-space buffer is allocated once
+/// and kept while the destinations' block sizes agree, and whether
+/// copy_file_range is worth trying is settled when the engine is made.
+/// `copy()` is a one-off use of an engine with the default options.
+pub struct CopyEngine {
+    opts: CopyOptions,
+    buf: Vec<u8>,
+}
+
+impl CopyEngine {
+    /// An engine making every copy with `opts`.
+    pub fn new(opts: CopyOptions) -> CopyEngine {
+        seed_copy_file_range();
+        CopyEngine { opts, buf: Vec::new() }
+    }
+
+    /// The options every copy is made with.
+    pub fn options(&self) -> &CopyOptions {
+        &self.opts
+    }
+
+    /// Copy `from` to `to`, as `copy_with_options()` would.
+    pub fn copy(&mut self, from: &Path, to: &Path) -> Result<u64, CopyError> {
+        let buf = Some(&mut self.buf);
+        Ok(copy_file(from, to, &self.opts, &mut |_, _| {}, None, None, buf)?.bytes_total)
+    }
+}
+
+/// Copy the contents of `from` to `to`. Returns the number of logical
+/// bytes copied, which is the source's length, counting holes the same
+/// whether they were skipped, left to the kernel or written as zeroes.
+pub fn copy(from: &Path, to: &Path) -> Result<u64, CopyError> {
+    CopyEngine::new(CopyOptions::default()).copy(from, to)
+}
+
+/// As `copy()`, but returns a breakdown of how the copy was performed.
+pub fn copy_stats(from: &Path, to: &Path) -> Result<CopyStats, CopyError> {
+    Ok(copy_file(from, to, &CopyOptions::default(), &mut |_, _| {}, None, None, None)?)
+}
+
+/// As `copy()`, but calls `cb` with `(bytes_copied_so_far, total_len)`
+/// after each chunk is copied. Holes skipped in sparse files are
+/// counted as copied.
+pub fn copy_with_progress<F>(from: &Path, to: &Path, mut cb: F) -> Result<u64, CopyError>
+    where F: FnMut(u64, u64)
+{
+    Ok(copy_file(from, to, &CopyOptions::default(), &mut cb, None, None, None)?.bytes_total)
+}
+
+/// As `copy()`, but with explicit control over how the data is copied.
+///
+/// The source is examined once, before anything is copied, and that
+/// decides both how it's copied and how much of it. If it's modified
+/// while it's being copied the result is best effort: the copy has the
+/// length the source had at the start, unless following growth, but may
+/// hold a mix of old and new data.
+pub fn copy_with_options(from: &Path, to: &Path,
+                         opts: &CopyOptions) -> Result<u64, CopyError> {
+    Ok(copy_ctx(from, to, opts)?)
+}
+
+/// As `copy_with_options()`, but where the error concerns one of the
+/// paths, such as a source that doesn't exist or a destination that
+/// can't be created, it says which.
+pub fn copy_ctx(from: &Path, to: &Path, opts: &CopyOptions) -> Result<u64, PathError> {
+    copy_file(from, to, opts, &mut |_, _| {}, None, None, None).map(|s| s.bytes_total)
+}
+
+/// As `copy_with_options()`, but stops with an error of kind
+/// `Interrupted` soon after `cancel` is set. The destination is left
+/// partially written unless `opts.remove_partial` is set.
+pub fn copy_cancellable(from: &Path, to: &Path, opts: &CopyOptions,
+                        cancel: &AtomicBool) -> Result<u64, CopyError> {
+    Ok(copy_file(from, to, opts, &mut |_, _| {}, Some(cancel), None, None)?.bytes_total)
+}
+
+/// As `copy()`, but also feeds the contents of the file through
+/// `hasher`, returning the number of bytes copied and the final hash.
+///
+/// The data has to pass through user-space to be hashed, so this never
+/// uses the kernel copy paths and is correspondingly slower. Holes in a
+/// sparse source are hashed as zeroes without being read.
+pub fn copy_hashed<H: Hasher>(from: &Path, to: &Path,
+                              hasher: &mut H) -> Result<(u64, u64), CopyError> {
+    let opts = CopyOptions { force_uspace: true, ..CopyOptions::default() };
+    let stats = copy_file(from, to, &opts, &mut |_, _| {}, None, Some(hasher), None)?;
+    Ok((stats.bytes_total, hasher.finish()))
+}
+
+/// Copy each `(from, to)` pair in turn as `copy_with_options()` would,
+/// returning a result for each. A failure only affects its own pair, and
+/// the rest of the batch is still copied. One user-space buffer is shared
+/// by the whole batch, and which kernel copies work is only found out
+/// once, which matters when there are many small files.
+pub fn copy_many(pairs: &[(PathBuf, PathBuf)], opts: &CopyOptions) -> Vec<io::Result<u64>> {
+    let mut engine = CopyEngine::new(opts.clone());
+    pairs.iter().map(|&(ref from, ref to)| {
+        engine.copy(from, to).map_err(io::Error::from)
+    }).collect()
+}
+
+/// Copy `from` to each of `dests` at their current positions, reading
+/// the source only once. Every destination receives each block before
+/// the next is read, so this always copies through user-space; the
+/// kernel can't duplicate a copy. Returns the number of bytes written
+/// to each destination.
+pub fn copy_tee(from: &Path, dests: &[&File]) -> Result<u64, TeeError> {
+    let mut infd = File::open(from)?;
+    let len = infd.metadata()?.len();
+
+    let mut buf = uspace_buffer(&infd, MIN_USPACE_BLKSIZE);
+    let mut failed = None;
+    copy_blocks(&mut infd, len, &mut buf, false, |buf| {
+        for (index, mut dest) in dests.iter().cloned().enumerate() {
+            if let Err(error) = dest.write_all(buf) {
+                failed = Some(index);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }).map_err(|error| TeeError { error, index: failed })
+}
+
+/// Recursively copy the directory `from` to `to`, which must not exist,
+/// using `copy()` for each file. Symlinks are recreated rather than
+/// followed, and files hard linked together are linked together again
+/// in the copy. Returns the total number of bytes copied.
+pub fn copy_tree(from: &Path, to: &Path) -> io::Result<u64> {
+    copy_tree_linked(from, to, &mut HashMap::new())
+}
+
+// `links` maps the (st_dev, st_ino) of each multiply linked file copied
+// so far to where it was copied.
+fn copy_tree_linked(from: &Path, to: &Path,
+                    links: &mut HashMap<(u64, u64), PathBuf>) -> io::Result<u64> {
+    let meta = fs::symlink_metadata(from)?;
+    if !meta.is_dir() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "the source path is not an existing directory"))
+    }
+
+    fs::create_dir(to)?;
+
+    let mut total = 0;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let src = entry.path();
+        let dst = to.join(entry.file_name());
+        let ftype = entry.file_type()?;
+
+        total += if ftype.is_dir() {
+            copy_tree_linked(&src, &dst, links)?
+        } else if ftype.is_symlink() {
+            symlink(fs::read_link(&src)?, &dst)?;
+            0
+        } else {
+            let meta = entry.metadata()?;
+            if meta.st_nlink() > 1 {
+                let key = (meta.st_dev(), meta.st_ino());
+                if let Some(first) = links.get(&key) {
+                    fs::hard_link(first, &dst)?;
+                    continue;
+                }
+                links.insert(key, dst.clone());
+            }
+            copy(&src, &dst)?
+        };
+    }
+
+    // Applied last in case the permissions deny writing.
+    fs::set_permissions(to, meta.permissions())?;
+    Ok(total)
+}
+
+/// As `copy()`, but splits the file into `threads` contiguous ranges
+/// that are copied concurrently, each thread with its own descriptors.
+/// Holes in the source are preserved. This is only useful for very large
+/// files on fast storage. Each range is copied with copy_file_range(2)
+/// where it works between the two files, and read and written otherwise.
+pub fn copy_parallel(from: &Path, to: &Path, threads: usize) -> io::Result<u64> {
+    if threads == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "at least one thread is needed"))
+    }
+    check_source_is_file(from)?;
+    let to = resolve_dest(from, to)?;
+
+    let in_meta = fs::metadata(from)?;
+    if let Ok(to_meta) = fs::metadata(&to) {
+        if to_meta.st_dev() == in_meta.st_dev() && to_meta.st_ino() == in_meta.st_ino() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "the source and destination are the same file"))
+        }
+    }
+
+    // Sizing the destination up front means the threads' writes don't
+    // serialize on extending it, and leaves holes where we skip.
+    let len = in_meta.len();
+    let outfd = File::create(&to)?;
+    allocate_file(&outfd, len)?;
+
+    let range = (len + threads as u64 - 1) / threads as u64;
+    let workers = (0..threads as u64)
+        .map(|i| (i * range, cmp::min((i + 1) * range, len)))
+        .filter(|&(start, end)| start < end)
+        .map(|(start, end)| {
+            let (from, to) = (from.to_path_buf(), to.clone());
+            thread::spawn(move || {
+                let infd = File::open(&from)?;
+                let outfd = OpenOptions::new().write(true).open(&to)?;
+                copy_range_at(&infd, &outfd, start, end)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Join every thread before reporting the first failure.
+    let mut result = Ok(());
+    for worker in workers {
+        let r = worker.join().unwrap_or_else(|_| {
+            Err(Error::new(ErrorKind::Other, "a copy thread panicked"))
+        });
+        if result.is_ok() {
+            result = r;
+        }
+    }
+    result?;
+
+    outfd.set_permissions(in_meta.permissions())?;
+    Ok(len)
+}
+
+// Copy the data in `start..end` of `infd` to the same offsets in
+// `outfd`, skipping over holes.
+fn copy_range_at(infd: &File, outfd: &File, start: u64, end: u64) -> io::Result<()> {
+    let mut pos = start;
+    while pos < end {
+        let (next_data, next_hole) = next_sparse_segments(infd, pos, end)?;
+        let (next_data, next_hole) = (cmp::min(next_data, end), cmp::min(next_hole, end));
+        let len = next_hole - next_data;
+        if copy_range_or_uspace_at(infd, outfd, next_data, next_data, len)? < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof,
+                                  "Source file ended prematurely."));
+        }
+        pos = next_hole;
+    }
+    Ok(())
+}
+
+// Recreate the device node `from` at `to`, which usually needs
+// CAP_MKNOD. The node is never opened, as that may have side effects.
+fn copy_special(from: &Path, to: &Path, opts: &CopyOptions) -> io::Result<()> {
+    let meta = fs::metadata(from)?;
+    if !opts.no_clobber {
+        match fs::remove_file(to) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            r => r?,
+        }
+    }
+
+    let path = CString::new(to.as_os_str().as_bytes())?;
+    cvt(unsafe {
+        libc::mknod(path.as_ptr(), meta.st_mode() as libc::mode_t, meta.st_rdev() as libc::dev_t)
+    })?;
+    // mknod is subject to the umask.
+    fs::set_permissions(to, meta.permissions())
+}
+
+// Whether `a` was modified strictly later than `b`.
+fn is_newer(a: &Metadata, b: &Metadata) -> bool {
+    (a.st_mtime(), a.st_mtime_nsec()) > (b.st_mtime(), b.st_mtime_nsec())
+}
+
+// The temporary file for an atomic copy to `to`. It must be in the same
+// directory for the rename to be atomic.
+fn atomic_temp_path(to: &Path) -> io::Result<PathBuf> {
+    let name = match to.file_name() {
+        Some(name) => name,
+        None => return Err(Error::new(ErrorKind::InvalidInput,
+                                      "the destination path has no file name")),
+    };
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(".tmp.{}", process::id()));
+    Ok(to.with_file_name(tmp_name))
+}
+
+// Like cp(1), copying to a directory places the file inside it.
+fn resolve_dest(from: &Path, to: &Path) -> io::Result<PathBuf> {
+    if !to.is_dir() {
+        return Ok(to.to_path_buf());
+    }
+    match from.file_name() {
+        Some(name) => Ok(to.join(name)),
+        None => Err(Error::new(ErrorKind::InvalidInput,
+                               "the source path has no file name")),
+    }
+}
+
+// Check a source of `len` bytes against `opts.max_output_bytes`.
+fn check_output_len(opts: &CopyOptions, len: u64) -> io::Result<()> {
+    match opts.max_output_bytes {
+        Some(max) if len > max => Err(output_limit_exceeded()),
+        _ => Ok(()),
+    }
+}
+
+fn output_limit_exceeded() -> io::Error {
+    Error::new(ErrorKind::Other, "output size limit exceeded")
+}
+
+// Copy everything written to the FIFO `infd` until its writers close
+// it. Its length isn't known up front and it can't be seeked, so this
+// always goes through the user-space loop, and the progress total is
+// just what's been read so far.
+fn drain_fifo(infd: &File, outfd: &File, opts: &CopyOptions, cb: &mut dyn FnMut(u64, u64),
+              cancel: Option<&AtomicBool>,
+              hasher: Option<&mut dyn Hasher>) -> Result<CopyStats, CopyError> {
+    let opts = CopyOptions { allow_short: true, ..opts.clone() };
+    let mut copied = 0;
+    let mut progress = |n| {
+        copied += n;
+        cb(copied, copied);
+    };
+
+    let mut state = CopyState::new(&opts, &mut progress);
+    state.stats.used_kernel_copy = false;
+    state.cancel = cancel;
+    if let Some(hasher) = hasher {
+        state.hasher = Some(hasher);
+    }
+
+    // Read up to the limit, then see whether there was any more.
+    let limit = opts.max_output_bytes.map_or(usize::max_value(), |max| {
+        cmp::min(max, usize::max_value() as u64) as usize
+    });
+    let result = copy_bytes_uspace(infd, outfd, limit, &opts, &mut state).and_then(|total| {
+        if opts.max_output_bytes == Some(total) && (&*infd).read(&mut [0; 1])? > 0 {
+            return Err(output_limit_exceeded());
+        }
+        Ok(total)
+    });
+    match result {
+        Ok(total) => state.stats.bytes_total = total,
+        Err(error) => return Err(CopyError {
+            error,
+            bytes_copied: state.stats.bytes_copied,
+        }),
+    }
+    state.stats.method = CopyMethod::UserSpace;
+    let stats = state.stats;
+
+    if opts.sync {
+        outfd.sync_all()?;
+    }
+    Ok(stats)
+}
+
+fn copy_file(from: &Path, to: &Path, opts: &CopyOptions, cb: &mut dyn FnMut(u64, u64),
+             cancel: Option<&AtomicBool>, hasher: Option<&mut dyn Hasher>,
+             buf: Option<&mut Vec<u8>>) -> Result<CopyStats, PathError> {
+    let to = &resolve_dest(from, to).map_err(|e| PathError::at(to, e))?;
+
+    let from_at = |e| PathError::at(from, e);
+    let to_at = |e| PathError::at(to, e);
+
+    if !opts.follow_symlinks &&
+        fs::symlink_metadata(from).map_err(from_at)?.file_type().is_symlink() {
+        let target = fs::read_link(from).map_err(from_at)?;
+        if !opts.dry_run {
+            symlink(&target, to).map_err(to_at)?;
+        }
+
+        let len = target.as_os_str().len() as u64;
+        cb(len, len);
+        return Ok(CopyStats {
+            bytes_copied: len,
+            bytes_total: len,
+            ..CopyStats::default()
+        });
+    }
+
+    if opts.copy_special {
+        let ftype = fs::metadata(from).map_err(from_at)?.file_type();
+        if ftype.is_char_device() || ftype.is_block_device() {
+            if !opts.dry_run {
+                copy_special(from, to, opts).map_err(to_at)?;
+            }
+            return Ok(CopyStats::default());
+        }
+    }
+
+    let is_fifo = opts.drain_fifo && fs::metadata(from).map_err(from_at)?.file_type().is_fifo();
+    if !is_fifo {
+        check_source_is_file(from).map_err(from_at)?;
+    }
+    if opts.dry_run && is_fifo {
+        // Opening it would wait for a writer, and what would be read
+        // isn't known until it's drained.
+        return Ok(CopyStats { method: CopyMethod::UserSpace, ..CopyStats::default() });
+    }
+
+    // A FIFO has no page cache to bypass.
+    let direct = opts.direct_io && !is_fifo;
+    let mut in_open = OpenOptions::new();
+    in_open.read(true);
+    if direct {
+        in_open.custom_flags(libc::O_DIRECT);
+    }
+    let infd = in_open.open(from).map_err(from_at)?;
+    let in_meta = fstat(&infd).map_err(from_at)?;
+
+    // Creating the destination would truncate the source before we
+    // read it.
+    if let Ok(to_meta) = fs::metadata(to) {
+        if to_meta.st_dev() == in_meta.st_dev() && to_meta.st_ino() == in_meta.st_ino() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "the source and destination are the same file").into())
+        }
+        // Decided before the destination is opened, which would
+        // truncate it.
+        if opts.update_only && !is_newer(&in_meta, &to_meta) {
+            return Ok(CopyStats::default());
+        }
+    }
+    // Before the destination is created, so nothing is left behind.
+    check_output_len(opts, in_meta.len()).map_err(from_at)?;
+
+    if opts.dry_run {
+        // A destination that doesn't exist yet would be on the same
+        // filesystem as its directory.
+        let out_meta = match fs::metadata(to) {
+            Ok(meta) => meta,
+            Err(_) => fs::metadata(parent_dir(to)).map_err(to_at)?,
+        };
+        let opts = copy_parms(&in_meta, &out_meta, opts)?;
+        return Ok(plan_copy(&infd, in_meta.len(), &opts)?);
+    }
+
+    if opts.create_parents {
+        let parent = parent_dir(to);
+        fs::create_dir_all(parent).map_err(|e| PathError::at(parent, e))?;
+    }
+
+    let tmp = if opts.atomic {
+        // The rename would replace it regardless.
+        if opts.no_clobber && fs::symlink_metadata(to).is_ok() {
+            return Err(to_at(Error::new(ErrorKind::AlreadyExists,
+                                        "the destination already exists")))
+        }
+        Some(atomic_temp_path(to).map_err(to_at)?)
+    } else {
+        None
+    };
+
+    let mut open = OpenOptions::new();
+    open.write(true).read(opts.verify);
+    if opts.no_clobber || tmp.is_some() {
+        // O_EXCL, so there's no window for the file to appear.
+        open.create_new(true);
+    } else if opts.presized {
+        // It must already exist, to be the right size.
+    } else if opts.no_truncate {
+        open.create(true);
+    } else {
+        open.create(true).truncate(true);
+    }
+    if direct {
+        open.custom_flags(libc::O_DIRECT);
+    }
+    let created = opts.no_clobber || tmp.is_some() || fs::symlink_metadata(to).is_err();
+    let outfd = match tmp {
+        Some(ref tmp) => {
+            open.open(tmp).map_err(|e| PathError::at(tmp, explain_missing_parent(tmp, e)))?
+        }
+        None => open.open(to).map_err(|e| to_at(explain_missing_parent(to, e)))?,
+    };
+
+    // An immutable or append-only file can't be renamed, so a temporary
+    // file only gets the flags once it has taken the destination's name.
+    let defer_flags = tmp.is_some() && opts.preserve_flags;
+    let fds_opts;
+    let opts = if defer_flags {
+        fds_opts = CopyOptions { preserve_flags: false, ..opts.clone() };
+        &fds_opts
+    } else {
+        opts
+    };
+
+    let result = if is_fifo {
+        drain_fifo(&infd, &outfd, opts, cb, cancel, hasher)
+    } else {
+        copy_fds(&infd, &in_meta, &outfd, opts, cb, cancel, hasher, buf)
+    };
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(err) => {
+            let cancelled = cancel.is_some() && err.kind() == ErrorKind::Interrupted;
+            // With no_truncate or presized an existing file still holds its
+            // own data.
+            let partial = created || !(opts.no_truncate || opts.presized);
+            if let Some(ref tmp) = tmp {
+                drop(outfd);
+                let _ = fs::remove_file(tmp);
+            } else if (opts.remove_on_error && partial) ||
+                (opts.remove_partial && created && cancelled) {
+                drop(outfd);
+                // Failing to remove it mustn't hide why the copy failed.
+                let _ = fs::remove_file(to);
+            }
+            return Err(err.into())
+        }
+    };
+
+    if let Some(ref tmp) = tmp {
+        // The data must be on disk before the rename is, or a crash
+        // could leave an empty or partial file under the final name.
+        let synced = if opts.sync { Ok(()) } else { outfd.sync_all() };
+        if let Err(err) = synced.and_then(|_| fs::rename(tmp, to)) {
+            drop(outfd);
+            let _ = fs::remove_file(tmp);
+            return Err(to_at(err))
+        }
+    }
+
+    if defer_flags {
+        match copy_inode_flags(&infd, &outfd) {
+            Err(err) if !opts.ignore_perm_errors => return Err(to_at(err)),
+            _ => {}
+        }
+        if opts.sync {
+            outfd.sync_all().map_err(to_at)?;
+        }
+    }
+
+    if opts.sync {
+        sync_parent(to).map_err(to_at)?;
+    }
+
+    Ok(stats)
+}
+
+/// As `copy_with_options()`, but between files that are already open.
+/// The whole of `infd` is copied to the start of `outfd`, which must
+/// also be open for reading if `opts.verify` is set. Options that
+/// concern opening the destination are ignored.
+pub fn copy_fd(infd: &File, outfd: &File, opts: &CopyOptions) -> Result<u64, CopyError> {
+    let in_meta = fstat(infd)?;
+    copy_fds(infd, &in_meta, outfd, opts, &mut |_, _| {}, None, None, None)
+        .map(|s| s.bytes_total)
+}
+
+/// Copy the whole of `infd` to the start of `outfd` through `buf`, so
+/// that nothing is allocated, e.g. for a buffer in memory set aside for
+/// it. The length of `buf` is the size of each read. This is always a
+/// plain user-space copy, without any of the `CopyOptions`, and anything
+/// in `outfd` beyond the copied data is left as it was.
+pub fn copy_fd_with_buffer(infd: &File, outfd: &File, buf: &mut [u8]) -> io::Result<u64> {
+    if buf.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "the copy buffer is empty"));
+    }
+    let len = fstat(infd)?.len();
+    lseek(infd, 0, Wence::Set)?;
+    lseek(outfd, 0, Wence::Set)?;
+
+    let opts = CopyOptions { force_uspace: true, ..CopyOptions::default() };
+    let mut progress = |_| {};
+    let mut state = CopyState::new(&opts, &mut progress);
+    let mut written = 0;
+    // In pieces a usize can hold, on 32-bit targets.
+    while written < len {
+        let nbytes = chunk_len(len - written);
+        written += copy_bytes_uspace_buf(infd, outfd, nbytes, buf, &opts, &mut state)?;
+    }
+    Ok(written)
+}
+
+/// Write the whole of `from` into `outfd` starting at `dst_offset`,
+/// leaving the rest of `outfd` as it was. The destination is never
+/// truncated, and is extended only if the data runs past its end. This
+/// uses copy_file_range(2) where it works between the two files, and
+/// reads and writes otherwise. Returns the number of bytes copied.
+pub fn copy_into_at(from: &Path, outfd: &File, dst_offset: u64) -> io::Result<u64> {
+    let infd = File::open(from)?;
+    let len = infd.metadata()?.len();
+    copy_range_or_uspace_at(&infd, outfd, 0, dst_offset, len)
+}
+
+/// As `copy()`, but into an already open `outfd`, so that the caller
+/// can choose how the destination is opened. Any existing contents of
+/// `outfd` are replaced, and the source's permissions are applied once
+/// the data has been copied.
+pub fn copy_to_open(from: &Path, outfd: &File) -> Result<u64, CopyError> {
+    let infd = File::open(from)?;
+    let opts = CopyOptions { no_truncate: true, ..CopyOptions::default() };
+    let in_meta = fstat(&infd)?;
+    copy_fds(&infd, &in_meta, outfd, &opts, &mut |_, _| {}, None, None, None)
+        .map(|s| s.bytes_total)
+}
+
+// The stats copy_fds() would return for the resolved `opts`, found
+// without writing anything.
+fn plan_copy(infd: &File, len: u64, opts: &CopyOptions) -> io::Result<CopyStats> {
+    let segments = if opts.preserve_sparse {
+        sparse_segments(infd)?
+    } else if len > 0 {
+        vec![(0, len)]
+    } else {
+        Vec::new()
+    };
+    let data = segments.iter().map(|&(start, end)| end - start).sum::<u64>();
+
+    Ok(CopyStats {
+        bytes_copied: data,
+        bytes_total: len,
+        holes_skipped: len - data,
+        data_segments: segments.len() as u64,
+        used_kernel_copy: !opts.force_uspace,
+        method: if opts.force_uspace { CopyMethod::UserSpace } else { CopyMethod::Kernel },
+        syscalls: SyscallCounts::default(),
+    })
+}
+
+/// Apply the metadata of `infd` to `outfd` without copying any data:
+/// the permissions, and the extended attributes, owner and times if
+/// `opts` asks for them.
+///
+/// The permissions are always applied last. A read-only or otherwise
+/// restrictive source mode would stop the other steps on filesystems
+/// that check it against the open descriptor, and setting the times
+/// after any write keeps them from being bumped again.
+pub fn copy_attributes(infd: &File, outfd: &File, opts: &CopyOptions) -> io::Result<()> {
+    apply_attributes(infd, &fstat(infd)?, outfd, opts)
+}
+
+// As copy_attributes(), given the metadata of `infd`.
+fn apply_attributes(infd: &File, in_meta: &Metadata, outfd: &File,
+                    opts: &CopyOptions) -> io::Result<()> {
+    let check = |r: io::Result<()>| if opts.ignore_perm_errors { Ok(()) } else { r };
+
+    if opts.preserve_xattrs {
+        check(copy_xattrs(infd, outfd))?;
+    }
+
+    if opts.preserve_owner {
+        check(copy_ownership(&in_meta, outfd))?;
+    }
+
+    if opts.preserve_times {
+        check(copy_times(&in_meta, outfd))?;
+    }
+
+    check(copy_mode_bits(&in_meta, outfd))?;
+
+    if opts.preserve_acl {
+        check(copy_acls(infd, outfd))?;
+    }
+
+    // Immutable and append-only files can't have anything else changed.
+    if opts.preserve_flags {
+        check(copy_inode_flags(infd, outfd))?;
+    }
+    Ok(())
+}
+
+/// Apply the source's permission bits to the destination, including
+/// the setuid, setgid and sticky bits. This must come after any change
+/// of owner, as chown clears the setuid and setgid bits.
+fn copy_mode_bits(in_meta: &Metadata, outfd: &File) -> io::Result<()> {
+    let mode = in_meta.st_mode() & 0o7777;
+    cvt_r(|| unsafe { libc::fchmod(outfd.as_raw_fd(), mode as libc::mode_t) })?;
+    Ok(())
+}
+
+// The source's metadata is taken once by the caller and used throughout,
+// so that every step sees the same length and mode. Whether to look for
+// holes is decided from the same snapshot, and either way the length
+// copied is the snapshot's, so a source that becomes sparse or dense in
+// the meantime is only copied by a slower path than it might have been.
+fn copy_fds(infd: &File, in_meta: &Metadata, outfd: &File, opts: &CopyOptions,
+            cb: &mut dyn FnMut(u64, u64),
+            cancel: Option<&AtomicBool>, hasher: Option<&mut dyn Hasher>,
+            mut buf: Option<&mut Vec<u8>>) -> Result<CopyStats, CopyError> {
+    let syscalls = SYSCALLS.with(|counts| counts.get());
+    let out_meta = fstat(outfd)?;
+
+    if !in_meta.is_file() {
+        return Err(not_regular_file(in_meta).into())
+    }
+    if out_meta.st_dev() == in_meta.st_dev() && out_meta.st_ino() == in_meta.st_ino() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "the source and destination are the same file").into())
+    }
+
+    check_output_len(opts, in_meta.len())?;
+    if opts.presized && out_meta.len() < in_meta.len() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "the destination is shorter than the source").into())
+    }
+
+    let mut opts = copy_parms(in_meta, &out_meta, opts)?;
+
+    if opts.dry_run {
+        return Ok(plan_copy(infd, in_meta.len(), &opts)?);
+    }
+
+    // Skipping holes on a destination that can't hold them would only
+    // leave it dense anyway, so just copy everything. Nor can one that
+    // isn't a regular file, which is never probed. If the probe itself
+    // fails the holes are kept, as they would be without it.
+    if opts.preserve_sparse && (!out_meta.is_file() ||
+        is_xmount(in_meta, &out_meta) && !supports_holes(outfd).unwrap_or(true)) {
+        opts.preserve_sparse = false;
+    }
+    // Only btrfs clones a segment at a time. A filesystem that can't be
+    // looked up is taken not to be btrfs, and the segments are copied.
+    let clone_segments = opts.preserve_sparse && !opts.force_uspace &&
+        on_btrfs(infd).unwrap_or(false) && on_btrfs(outfd).unwrap_or(false);
+
+    lseek(infd, 0, Wence::Set)?;
+    lseek(outfd, 0, Wence::Set)?;
+
+    if opts.drop_cache {
+        fadvise(infd, libc::POSIX_FADV_SEQUENTIAL);
+        fadvise(outfd, libc::POSIX_FADV_SEQUENTIAL);
+    }
+
+    let len = in_meta.len();
+    let mut copied = 0;
+    let mut progress = |n| {
+        copied += n;
+        cb(copied, len);
+    };
+
+    if opts.preallocate && len > 0 {
+        preallocate_file(&outfd, len)?;
+    }
+
+    let mut state = CopyState::new(&opts, &mut progress);
+    state.cancel = cancel;
+    state.clone_segments = clone_segments;
+    if let Some(hasher) = hasher {
+        state.hasher = Some(hasher);
+    }
+    // Borrow the caller's buffer for the user-space copies, if it has one.
+    if let Some(ref mut buf) = buf {
+        state.buf = mem::replace(*buf, Vec::new());
+    }
+
+    // A clone would replace the whole of a presized destination.
+    let result = if !opts.force_uspace && !opts.presized && try_reflink(infd, outfd)? {
+        state.copied(CopyMethod::Kernel, len);
+        state.stats.data_segments = cmp::min(len, 1);
+        Ok(len)
+
+    } else if opts.direct_io {
+        state.stats.data_segments = cmp::min(len, 1);
+        let align = cmp::max(in_meta.st_blksize(), out_meta.st_blksize()) as usize;
+        copy_direct(infd, outfd, &opts, len, align, &mut state)
+
+    } else if opts.preserve_sparse {
+        copy_sparse(infd, outfd, &opts, len, &mut state)
+
+    } else {
+        state.stats.data_segments = cmp::min(len, 1);
+        copy_range(infd, outfd, &opts, len, &mut state)
+    };
+    if let Some(buf) = buf {
+        *buf = mem::replace(&mut state.buf, Vec::new());
+    }
+    state.stats.bytes_total = match result {
+        Ok(total) => total,
+        Err(error) => return Err(CopyError {
+            error,
+            bytes_copied: state.stats.bytes_copied + state.stats.holes_skipped,
+        }),
+    };
+    state.stats.method = state.method();
+    let mut stats = state.stats;
+
+    // Remove anything beyond the copied data from an existing file, or
+    // extend the file over a trailing run of punched zeroes.
+    if (opts.no_truncate || opts.punch_zeros) && !opts.presized {
+        allocate_file(outfd, stats.bytes_total)?;
+    }
+
+    if opts.verify {
+        verify_copy(infd, outfd, opts.block_size.unwrap_or(MIN_USPACE_BLKSIZE))?;
+    }
+
+    if opts.drop_cache {
+        fadvise(infd, libc::POSIX_FADV_DONTNEED);
+    }
+
+    // Nothing may write to the destination after this: its times are
+    // final, and its mode may no longer allow it.
+    apply_attributes(infd, in_meta, outfd, &opts)?;
+
+    // A full fsync rather than fdatasync so the permissions are
+    // persisted along with the data.
+    if opts.sync {
+        outfd.sync_all()?;
+    }
+
+    if opts.instrument {
+        stats.syscalls = SYSCALLS.with(|counts| counts.get()).since(&syscalls);
+    }
+    Ok(stats)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iter;
+    use mem;
+    use ffi::CString;
+    use os::unix::ffi::OsStrExt;
+    use os::unix::fs::PermissionsExt;
+    use os::unix::io::FromRawFd;
+    use sys_common::io::test::{TempDir, tmpdir};
+    use sys_common::mutex::MutexGuard;
+    use fs::{read, remove_file, OpenOptions};
+    use io::{Seek, SeekFrom, Write};
+    use path::PathBuf;
+    use process;
+    use sync::Arc;
+    use sync::atomic::AtomicBool;
+
+
+    fn create_sparse(file: &PathBuf, len: u64) {
+        let fd = File::create(file).unwrap();
+        allocate_file(&fd, len).unwrap();
+    }
+
+    fn create_sparse_with_data(file: &PathBuf, head: u64, tail: u64) -> u64 {
+        let data = "c00lc0d3";
+        let len = 4096u64 * 4096 + data.len() as u64 + tail;
+
+        {
+            let fd = File::create(file).unwrap();
+            allocate_file(&fd, len).unwrap();
+        }
+
+        let mut fd = OpenOptions::new()
+            .write(true)
+            .append(false)
+            .open(&file).unwrap();
+
+        fd.seek(SeekFrom::Start(head)).unwrap();
+        write!(fd, "{}", data).unwrap();
+
+        fd.seek(SeekFrom::Start(1024*4096)).unwrap();
+        write!(fd, "{}", data).unwrap();
+
+        fd.seek(SeekFrom::Start(4096*4096)).unwrap();
+        write!(fd, "{}", data).unwrap();
+
+        len
+    }
+
+
+    fn tmps(dir: &TempDir) -> (PathBuf, PathBuf) {
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        (from, to)
+    }
+
+    // A new directory called `name` on another mount than `dir`, e.g. a
+    // tmpfs, or None if there's nowhere writable. The caller removes it.
+    fn other_mount(dir: &TempDir, name: &str) -> Option<PathBuf> {
+        let dev = dir.path().metadata().unwrap().st_dev();
+        let other = ["/dev/shm", "/run/shm", "/run/user", "/tmp"].iter()
+            .map(Path::new)
+            .find(|p| p.metadata().map(|m| m.st_dev() != dev).unwrap_or(false))?
+            .join(format!("fs_linux-{}-{}", name, process::id()));
+        fs::create_dir(&other).ok().map(|_| other)
+    }
+
+    // A do-nothing SIGCONT handler without SA_RESTART, so that blocking
+    // calls interrupted by the signal fail with EINTR. The handler found
+    // is put back on drop, and the tests installing one take turns so
+    // that none puts back another's.
+    struct SigcontHandler {
+        old: libc::sigaction,
+        _turn: MutexGuard<'static>,
+    }
+
+    static SIGCONT_TURN: Mutex = Mutex::new();
+
+    impl SigcontHandler {
+        fn install() -> SigcontHandler {
+            extern "C" fn handler(_: libc::c_int) {}
+
+            unsafe {
+                let turn = SIGCONT_TURN.lock();
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                let mut old = mem::zeroed();
+                cvt(libc::sigaction(libc::SIGCONT, &action, &mut old)).unwrap();
+                SigcontHandler { old, _turn: turn }
+            }
+        }
+    }
+
+    impl Drop for SigcontHandler {
+        fn drop(&mut self) {
+            unsafe { libc::sigaction(libc::SIGCONT, &self.old, ptr::null_mut()) };
+        }
+    }
+
+
+    // A test that can't run here says why, rather than passing silently.
+    // The reason shows with --nocapture.
+    fn skip(why: &str) {
+        eprintln!("{}: skipped, {}", thread::current().name().unwrap_or("test"), why);
+    }
+
+    // copy_file() with nothing but the options, for the stats.
+    fn copy_opts(from: &Path, to: &Path, opts: &CopyOptions) -> Result<CopyStats, PathError> {
+        copy_file(from, to, opts, &mut |_, _| {}, None, None, None)
+    }
+
+    fn is_sparse(fd: &File) -> io::Result<bool> {
+        let stat = fd.metadata()?;
+        Ok(stat.st_blocks() < stat.st_size() / stat.st_blksize())
+    }
+
+    fn is_fsparse(file: &PathBuf) -> io::Result<bool> {
+        let fd = File::open(file)?;
+        is_sparse(&fd)
+    }
+
+    // The `(data_start, data_end)` extents of `file`, so that tests can
+    // check holes are in the same place and not just that there are some.
+    fn allocated_extents(file: &PathBuf) -> Vec<(u64, u64)> {
+        sparse_segments(&File::open(file).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sparse_detection() {
+        assert!(!is_sparse(&File::open("Cargo.toml").unwrap()).unwrap());
+
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        create_sparse_with_data(&from, 0, 0);
+
+        {
+            let fd = File::open(&from).unwrap();
+            assert!(is_sparse(&fd).unwrap());
+        }
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .append(true)
+                .open(&from).unwrap();
+            write!(fd, "{}", "test").unwrap();
+        }
+        {
+            let fd = File::open(&from).unwrap();
+            assert!(is_sparse(&fd).unwrap());
+        }
+    }
+
+    fn test_copy_range(uspace: bool) {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = "test data";
+
+        {
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        create_sparse_with_data(&from, 0, 0);
+
+        {
+            let infd = File::open(&to).unwrap();
+            let outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&from).unwrap();
+            let opts = CopyOptions {
+                force_uspace: uspace,
+                kernel_copy_min_bytes: 0,
+                ..CopyOptions::default()
+            };
+            copy_range(&infd, &outfd, &opts, data.len() as u64,
+                       &mut CopyState::new(&opts, &mut |_| {})).unwrap();
+        }
+
+        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_meta_is_sparse() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "{}", "0123456789").unwrap();
+        }
+        assert!(!meta_is_sparse(&from.metadata().unwrap()));
+
+        File::create(&to).unwrap();
+        assert!(!meta_is_sparse(&to.metadata().unwrap()));
+
+        create_sparse_with_data(&to, 0, 0);
+        assert!(meta_is_sparse(&to.metadata().unwrap()));
+    }
+
+    #[test]
+    fn test_supports_holes() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "{}", "0123456789").unwrap();
+        }
+
+        let fd = OpenOptions::new().write(true).open(&from).unwrap();
+        assert!(probe_holes(&fd).unwrap());
+        assert_eq!(fd.metadata().unwrap().len(), 10);
+        assert!(supports_holes(&fd).unwrap());
+        let dev = fd.metadata().unwrap().st_dev();
+        unsafe {
+            let _guard = HOLES_LOCK.lock();
+            assert_eq!(HOLES_BY_DEV.as_ref().unwrap().get(&dev), Some(&true));
+        }
+        assert_eq!(read(&from).unwrap(), b"0123456789");
+    }
+
+    #[test]
+    fn test_copy_sparse_dense() {
+        // The path taken when the destination can't hold holes.
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        let opts = CopyOptions {
+            force_uspace: true,
+            preserve_sparse: false,
+            ..CopyOptions::default()
+        };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), len);
+
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+        assert!(!is_sparse(&File::open(&to).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_copy_empty() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        File::create(&from).unwrap();
+        {
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", "stale").unwrap();
+        }
+
+        let stats = copy_stats(&from, &to).unwrap();
+        assert_eq!(stats.bytes_total, 0);
+        assert_eq!(stats.bytes_copied, 0);
+        assert_eq!(to.metadata().unwrap().len(), 0);
+
+        let infd = File::open(&from).unwrap();
+        let outfd = File::create(&to).unwrap();
+        let opts = CopyOptions::default();
+        assert_eq!(copy_sparse(&infd, &outfd, &opts, 0,
+                               &mut CopyState::new(&opts, &mut |_| {})).unwrap(), 0);
+        assert_eq!(copy_range(&infd, &outfd, &opts, 0,
+                              &mut CopyState::new(&opts, &mut |_| {})).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_copy_direct_io() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // Not every filesystem supports O_DIRECT.
+        let probe = OpenOptions::new().write(true).create(true)
+            .custom_flags(libc::O_DIRECT).open(&from);
+        if probe.is_err() {
+            return skip("no O_DIRECT on this filesystem");
+        }
+
+        let opts = CopyOptions { direct_io: true, ..CopyOptions::default() };
+        // Aligned, with an unaligned tail, and shorter than one block.
+        for &len in &[256 * 1024, 256 * 1024 + 1000, 100] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            fs::write(&from, &data).unwrap();
+
+            let mut progress = |_, _| {};
+            let stats = copy_file(&from, &to, &opts, &mut progress, None, None, None).unwrap();
+            assert_eq!(stats.bytes_total, len as u64);
+            assert_eq!(stats.method, CopyMethod::UserSpace);
+            assert!(!stats.used_kernel_copy);
+            assert!(read(&to).unwrap() == data);
+        }
+    }
+
+    #[test]
+    fn test_copy_atomic() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        fs::write(&from, b"new contents").unwrap();
+        fs::write(&to, b"old").unwrap();
+        let names = || {
+            let mut names = fs::read_dir(dir.path()).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let opts = CopyOptions { atomic: true, ..CopyOptions::default() };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 12);
+        assert_eq!(read(&to).unwrap(), b"new contents");
+        assert_eq!(names(), ["from.bin", "to.bin"]);
+
+        // A failed copy leaves the old file in place and no temporary.
+        fs::write(&to, b"old").unwrap();
+        let cancel = AtomicBool::new(true);
+        let err = copy_cancellable(&from, &to, &opts, &cancel).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        assert_eq!(read(&to).unwrap(), b"old");
+        assert_eq!(names(), ["from.bin", "to.bin"]);
+
+        let opts = CopyOptions { no_clobber: true, ..opts };
+        let err = copy_with_options(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_copy_update_only() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let set_mtime = |path: &PathBuf, sec, nsec| {
+            let fd = OpenOptions::new().write(true).open(path).unwrap();
+            let times = [
+                libc::timespec { tv_sec: sec, tv_nsec: nsec },
+                libc::timespec { tv_sec: sec, tv_nsec: nsec },
+            ];
+            cvt(unsafe { libc::futimens(fd.as_raw_fd(), times.as_ptr()) }).unwrap();
+        };
+        let opts = CopyOptions { update_only: true, ..CopyOptions::default() };
+
+        // Copied if the destination is missing.
+        fs::write(&from, b"source").unwrap();
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 6);
+        assert_eq!(read(&to).unwrap(), b"source");
+
+        // Skipped, and left intact, if it's as new or newer.
+        fs::write(&to, b"destination").unwrap();
+        set_mtime(&from, 1_000_000_000, 500);
+        set_mtime(&to, 1_000_000_000, 500);
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 0);
+        set_mtime(&to, 1_000_000_001, 0);
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 0);
+        assert_eq!(read(&to).unwrap(), b"destination");
+
+        // Copied if the source is newer, even by a nanosecond.
+        set_mtime(&to, 1_000_000_000, 499);
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 6);
+        assert_eq!(read(&to).unwrap(), b"source");
+    }
+
+    #[test]
+    fn test_copy_many() {
+        let dir = tmpdir();
+        let mut pairs = Vec::new();
+        for i in 0..5 {
+            let from = dir.join(&format!("from{}.bin", i));
+            if i != 2 {
+                fs::write(&from, &vec![i as u8; 1000 * i + 1]).unwrap();
+            }
+            pairs.push((from, dir.join(&format!("to{}.bin", i))));
+        }
+
+        let opts = CopyOptions { force_uspace: true, ..CopyOptions::default() };
+        let results = copy_many(&pairs, &opts);
+        assert_eq!(results.len(), 5);
+        for (i, result) in results.iter().enumerate() {
+            let (ref from, ref to) = pairs[i];
+            if i == 2 {
+                assert_eq!(result.as_ref().unwrap_err().kind(), ErrorKind::InvalidInput);
+                assert!(!to.exists());
+            } else {
+                assert_eq!(*result.as_ref().unwrap(), 1000 * i as u64 + 1);
+                assert_eq!(read(from).unwrap(), read(to).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_engine() {
+        let dir = tmpdir();
+        let opts = CopyOptions { force_uspace: true, ..CopyOptions::default() };
+        let mut pairs = Vec::new();
+        for i in 0..4 {
+            let from = dir.join(&format!("from{}.bin", i));
+            fs::write(&from, &vec![i as u8; 64 * 1024 + i]).unwrap();
+            pairs.push((from, dir.join(&format!("to{}.bin", i))));
+        }
+
+        // The buffer is allocated by the first copy and kept for the rest.
+        let mut engine = CopyEngine::new(opts);
+        assert!(engine.buf.is_empty());
+        let mut first = None;
+        for (i, &(ref from, ref to)) in pairs.iter().enumerate() {
+            assert_eq!(engine.copy(from, to).unwrap(), 64 * 1024 + i as u64);
+            assert_eq!(read(from).unwrap(), read(to).unwrap());
+            assert_eq!(engine.buf.len(), MIN_USPACE_BLKSIZE);
+            let buf = engine.buf.as_ptr();
+            assert_eq!(*first.get_or_insert(buf), buf);
+        }
+        assert!(engine.options().force_uspace);
+    }
+
+    #[test]
+    fn test_copy_ctx_names_path() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let opts = CopyOptions::default();
+
+        let err = copy_ctx(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.path.as_ref(), Some(&from));
+        assert!(err.to_string().contains(from.to_str().unwrap()));
+
+        File::create(&from).unwrap();
+        let to = dir.join("missing").join("to.bin");
+        let err = copy_ctx(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.path.as_ref(), Some(&to));
+        assert!(err.to_string().contains(to.to_str().unwrap()));
+
+        // The same file is neither path's fault.
+        let err = copy_ctx(&from, &from, &opts).unwrap_err();
+        assert_eq!(err.path, None);
+    }
+
+    #[test]
+    fn test_is_xmount() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        File::create(&from).unwrap();
+        File::create(&to).unwrap();
+
+        let from_meta = from.metadata().unwrap();
+        assert!(!is_xmount(&from_meta, &from_meta));
+        assert!(!is_xmount(&from_meta, &to.metadata().unwrap()));
+        assert!(!is_xmount(&from_meta, &dir.path().metadata().unwrap()));
+
+        // procfs is always a filesystem of its own.
+        let proc_meta = Path::new("/proc").metadata().unwrap();
+        assert!(is_xmount(&from_meta, &proc_meta));
+        assert!(is_xmount(&proc_meta, &from_meta));
+    }
+
+    #[test]
+    fn test_copy_xmount() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let other = match other_mount(&dir, "xmount") {
+            Some(other) => other,
+            None => return skip("no other filesystem to copy to"),
+        };
+        let to = other.join("to.bin");
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        let result = copy(&from, &to);
+        let reflinked = {
+            let infd = File::open(&from).unwrap();
+            let outfd = OpenOptions::new().write(true).create(true).open(&to).unwrap();
+            try_reflink(&infd, &outfd)
+        };
+        let same = read(&from).unwrap() == read(&to).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+
+        assert_eq!(result.unwrap(), len);
+        assert!(!reflinked.unwrap());
+        assert!(same);
+    }
+
+    #[test]
+    fn test_copy_xmount_kernel() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let other = match other_mount(&dir, "xmount-kernel") {
+            Some(other) => other,
+            None => return skip("no other filesystem to copy to"),
+        };
+        let to = other.join("to.bin");
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        let opts = CopyOptions {
+            allow_xmount_kernel_copy: true,
+            kernel_copy_min_bytes: 0,
+            instrument: true,
+            ..CopyOptions::default()
+        };
+        let result = copy_opts(&from, &to, &opts);
+        let same = read(&from).unwrap() == read(&to).unwrap();
+        let sparse = is_sparse(&File::open(&to).unwrap()).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+
+        let stats = result.unwrap();
+        assert_eq!(stats.bytes_total, len);
+        assert!(stats.syscalls.copy_file_range > 0);
+        assert!(same);
+        assert!(sparse);
+    }
+
+    #[test]
+    fn test_copy_instrument() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // Three 64k data segments, the last running to the end.
+        {
+            let fd = File::create(&from).unwrap();
+            for i in 0..3 {
+                fd.write_at(&[i as u8 + 1; 64 * 1024], i * 1024 * 1024).unwrap();
+            }
+        }
+        let infd = File::open(&from).unwrap();
+        if sparse_segments(&infd).unwrap().len() != 3 {
+            return skip("the filesystem merged the segments");
+        }
+
+        let opts = CopyOptions {
+            force_uspace: true,
+            block_size: Some(64 * 1024),
+            instrument: true,
+            ..CopyOptions::default()
+        };
+        let mut progress = |_, _| {};
+        let stats = copy_file(&from, &to, &opts, &mut progress, None, None, None).unwrap();
+        assert_eq!(stats.data_segments, 3);
+        // Two to rewind the files, then for each segment two to find its
+        // data and the hole after it, and two to move both files there.
+        assert_eq!(stats.syscalls.lseek, 2 + 3 * 4);
+        assert_eq!(stats.syscalls.read, 3);
+        assert_eq!(stats.syscalls.write, 3);
+        assert_eq!(stats.syscalls.copy_file_range, 0);
+        assert_eq!(stats.syscalls.sendfile, 0);
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+
+        // Nothing is counted unless asked for.
+        let opts = CopyOptions { instrument: false, ..opts };
+        let stats = copy_file(&from, &to, &opts, &mut progress, None, None, None).unwrap();
+        assert_eq!(stats.syscalls, SyscallCounts::default());
+    }
+
+    #[test]
+    fn test_copy_fstat_once() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let len = create_sparse_with_data(&from, 0, 0);
+
+        let opts = CopyOptions {
+            instrument: true,
+            preserve_times: true,
+            ..CopyOptions::default()
+        };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, len);
+        // The destination, once to check it and once for the sparse copy
+        // to size it. The source's metadata was already taken on opening.
+        assert_eq!(stats.syscalls.fstat, 2);
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+        let (from_meta, to_meta) = (from.metadata().unwrap(), to.metadata().unwrap());
+        assert_eq!(to_meta.st_mtime(), from_meta.st_mtime());
+        assert_eq!(to_meta.permissions().mode(), from_meta.permissions().mode());
+    }
+
+    #[test]
+    fn test_copy_force_sparse() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // Dense, but mostly zeroes.
+        let mut data = vec![0; 4 * 1024 * 1024];
+        data[..4096].iter_mut().for_each(|b| *b = 1);
+        data[3 * 1024 * 1024..3 * 1024 * 1024 + 4096].iter_mut().for_each(|b| *b = 2);
+        fs::write(&from, &data).unwrap();
+        assert!(!super::is_sparse(&from).unwrap());
+
+        let opts = CopyOptions { instrument: true, ..CopyOptions::default() };
+        let dense = copy_opts(&from, &to, &opts).unwrap();
+        let opts = CopyOptions { force_sparse: true, ..opts };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.data_segments, 1);
+        assert_eq!(stats.holes_skipped, 0);
+        // Finding the one segment, and moving both files to its start.
+        assert_eq!(stats.syscalls.lseek, dense.syscalls.lseek + 4);
+        assert!(read(&to).unwrap() == data);
+
+        let opts = CopyOptions { punch_zeros: true, ..opts };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, data.len() as u64);
+        assert!(read(&to).unwrap() == data);
+        assert!(super::is_sparse(&to).unwrap());
+    }
+
+    #[test]
+    fn test_copy_sparse_coalesce() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // Eight 4k islands 16k apart, then a long hole and one more.
+        {
+            let fd = File::create(&from).unwrap();
+            for i in 0..8 {
+                fd.write_at(&[i as u8 + 1; 4096], i * 16 * 1024).unwrap();
+            }
+            fd.write_at(&[9; 4096], 1024 * 1024).unwrap();
+        }
+        if sparse_segments(&File::open(&from).unwrap()).unwrap().len() != 9 {
+            return skip("the filesystem merged the segments");
+        }
+
+        let copy_with = |min_hole_len| {
+            let opts = CopyOptions {
+                force_uspace: true,
+                instrument: true,
+                min_hole_len: Some(min_hole_len),
+                ..CopyOptions::default()
+            };
+            let stats = copy_opts(&from, &to, &opts).unwrap();
+            assert!(read(&from).unwrap() == read(&to).unwrap());
+            stats
+        };
+
+        let apart = copy_with(0);
+        assert_eq!(apart.data_segments, 9);
+        let joined = copy_with(64 * 1024);
+        assert_eq!(joined.data_segments, 2);
+        assert_eq!(joined.holes_skipped, 1024 * 1024 - (7 * 16 + 4) * 1024);
+        assert!(joined.syscalls.lseek < apart.syscalls.lseek);
+        assert_eq!(allocated_extents(&to).len(), 2);
+    }
+
+    // Three separate data segments, each copied in its own call.
+    fn create_three_segments(file: &PathBuf) {
+        let fd = File::create(file).unwrap();
+        for i in 0..3u64 {
+            let data: Vec<u8> = (0..64 * 1024).map(|j| (i * 7 + j % 251) as u8).collect();
+            fd.write_at(&data, i * 1024 * 1024).unwrap();
+        }
+        allocate_file(&fd, 3 * 1024 * 1024).unwrap();
+    }
+
+    #[test]
+    fn test_copy_kernel_refused() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let other = match other_mount(&dir, "kernel-refused") {
+            Some(other) => other,
+            None => return skip("no other filesystem to copy to"),
+        };
+        let to = other.join("to.bin");
+        create_three_segments(&from);
+
+        // Newer kernels refuse copy_file_range between filesystems of
+        // different types with EXDEV, once the copy is under way.
+        let opts = CopyOptions {
+            allow_xmount_kernel_copy: true,
+            kernel_copy_min_bytes: 0,
+            instrument: true,
+            ..CopyOptions::default()
+        };
+        let result = copy_opts(&from, &to, &opts);
+        let same = read(&from).unwrap() == read(&to).unwrap();
+        let extents = allocated_extents(&to);
+        fs::remove_dir_all(&other).unwrap();
+
+        let stats = result.unwrap();
+        assert_eq!(stats.bytes_total, 3 * 1024 * 1024);
+        assert!(same);
+        assert_eq!(extents, allocated_extents(&from));
+        if stats.method != CopyMethod::Kernel {
+            // Only tried on the first segment.
+            assert_eq!(stats.syscalls.copy_file_range, 1);
+        }
+    }
+
+    #[test]
+    fn test_copy_kernel_fallback_syscalls() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let other = match other_mount(&dir, "kernel-fallback") {
+            Some(other) => other,
+            None => return skip("no other filesystem to copy to"),
+        };
+        let to = other.join("to.bin");
+        create_three_segments(&from);
+
+        // Not trying the kernel at all, for comparison.
+        let opts = CopyOptions {
+            allow_xmount_kernel_copy: true,
+            kernel_copy_min_bytes: u64::max_value(),
+            instrument: true,
+            ..CopyOptions::default()
+        };
+        let direct = copy_opts(&from, &to, &opts);
+        let opts = CopyOptions { kernel_copy_min_bytes: 0, ..opts };
+        let result = copy_opts(&from, &to, &opts);
+        let same = read(&from).unwrap() == read(&to).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+
+        let (direct, stats) = (direct.unwrap(), result.unwrap());
+        assert_eq!(stats.bytes_total, direct.bytes_total);
+        assert_eq!(stats.data_segments, direct.data_segments);
+        assert!(same);
+        if stats.syscalls.copy_file_range == 1 {
+            // The refused call on the first segment is followed by putting
+            // the cursors back once, as is each sendfile that fails in turn.
+            // Otherwise the segments go straight to the method that works,
+            // with nothing to put back.
+            let failed = match stats.method {
+                CopyMethod::UserSpace => 1 + stats.syscalls.sendfile,
+                _ => 1,
+            };
+            assert_eq!(stats.syscalls.lseek, direct.syscalls.lseek + 2 * failed);
+        }
+    }
+
+    #[test]
+    fn test_copy_source_modified() {
+        fn fill_hole_and_grow(fd: &File) {
+            fd.write_at(b"filled", 2048 * 4096).unwrap();
+            let len = fd.metadata().unwrap().len();
+            fd.write_at(b"appended", len).unwrap();
+        }
+        fn make_dense(fd: &File) {
+            preallocate_file(fd, fd.metadata().unwrap().len()).unwrap();
+        }
+
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        for &modify in &[fill_hole_and_grow as fn(&File), make_dense] {
+            let len = create_sparse_with_data(&from, 0, 0);
+            assert!(super::is_sparse(&from).unwrap());
+
+            // Change the source once the copy is under way, after its first
+            // block of data.
+            let mut modified = false;
+            let result = copy_file(&from, &to, &CopyOptions::default(), &mut |_, _| {
+                if !modified {
+                    modify(&OpenOptions::new().write(true).open(&from).unwrap());
+                    modified = true;
+                }
+            }, None, None, None);
+            assert!(modified);
+
+            // The block already copied is unchanged, and the rest is copied
+            // as the source now is, up to its old length.
+            assert_eq!(result.unwrap().bytes_total, len);
+            let data = read(&from).unwrap();
+            assert!(read(&to).unwrap() == &data[..len as usize]);
+        }
+    }
+
+    #[test]
+    fn test_copy_counts_logical_bytes() {
+        if !kernel_supports_copy_file_range() {
+            return skip("no copy_file_range in this kernel");
+        }
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let len = create_sparse_with_data(&from, 0, 0);
+
+        // The kernel is handed the whole file, holes and all.
+        let opts = CopyOptions {
+            preserve_sparse: false,
+            kernel_copy_min_bytes: 0,
+            ..CopyOptions::default()
+        };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, len);
+        assert_eq!(stats.bytes_copied, len);
+        assert_eq!(stats.holes_skipped, 0);
+        assert!(stats.used_kernel_copy);
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+
+        // Only the data is, but the count is the same.
+        let opts = CopyOptions { preserve_sparse: true, ..opts };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, len);
+        assert!(stats.holes_skipped > 0);
+        assert_eq!(stats.bytes_copied + stats.holes_skipped, len);
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), len);
+    }
+
+    #[test]
+    fn test_copy_drop_cache() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        let opts = CopyOptions { drop_cache: true, ..CopyOptions::default() };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), len);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+
+        let opts = CopyOptions { force_uspace: true, ..opts };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), len);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
+    #[test]
+    fn test_copy_parallel() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let len = 16 * 1024 * 1024 + 123;
+
+        {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&data).unwrap();
+        }
+
+        assert_eq!(copy_parallel(&from, &to, 4).unwrap(), len as u64);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+
+        // More threads than bytes.
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "{}", "abc").unwrap();
+        }
+        assert_eq!(copy_parallel(&from, &to, 8).unwrap(), 3);
+        assert_eq!(read(&to).unwrap(), b"abc");
+
+        assert_eq!(copy_parallel(&from, &to, 0).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_copy_parallel_sparse() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 1024 * 1024);
+        assert_eq!(copy_parallel(&from, &to, 4).unwrap(), len);
+        assert_eq!(to.metadata().unwrap().len(), len);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+        assert!(is_sparse(&File::open(&to).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_copy_parallel_xmount() {
+        // Where copy_file_range fails with EXDEV the ranges are read and
+        // written instead.
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let other = match other_mount(&dir, "parallel") {
+            Some(other) => other,
+            None => return skip("no other filesystem to copy to"),
+        };
+        let to = other.join("to.bin");
+
+        let len = create_sparse_with_data(&from, 0, 1024 * 1024);
+        let result = copy_parallel(&from, &to, 4);
+        let same = read(&from).unwrap() == read(&to).unwrap();
+        let sparse = is_fsparse(&to);
+        fs::remove_dir_all(&other).unwrap();
+
+        assert_eq!(result.unwrap(), len);
+        assert!(same);
+        assert!(sparse.unwrap());
+    }
+
+    #[test]
+    fn test_copy_sparse_trailing_hole() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 1024 * 1024);
+        let segments = sparse_segments(&File::open(&from).unwrap()).unwrap();
+        assert!(segments.last().unwrap().1 < len);
+
+        assert_eq!(copy(&from, &to).unwrap(), len);
+        assert_eq!(to.metadata().unwrap().len(), len);
+        assert!(is_sparse(&File::open(&to).unwrap()).unwrap());
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
+    #[test]
+    fn test_is_sparse_path() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        create_sparse_with_data(&from, 0, 0);
+        assert!(super::is_sparse(&from).unwrap());
+
+        File::create(&to).unwrap();
+        assert!(!super::is_sparse(&to).unwrap());
+        fs::write(&to, &vec![1; 64 * 1024]).unwrap();
+        assert!(!super::is_sparse(&to).unwrap());
+
+        let missing = dir.join("missing");
+        assert_eq!(super::is_sparse(&missing).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_copy_sparse_many_segments() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let segments = 500;
+        let stride = 64 * 1024;
+        {
+            let fd = File::create(&from).unwrap();
+            for i in 0..segments {
+                fd.write_at(&[i as u8 | 1; 100], i * stride).unwrap();
+            }
+            allocate_file(&fd, segments * stride).unwrap();
+        }
+        let infd = File::open(&from).unwrap();
+        if sparse_segments(&infd).unwrap().len() != segments as usize {
+            return skip("the filesystem merged the segments");
+        }
+        let outfd = File::create(&to).unwrap();
+
+        let opts = CopyOptions {
+            force_uspace: true,
+            block_size: Some(8192),
+            ..CopyOptions::default()
+        };
+        let mut progress = |_| {};
+        let mut state = CopyState::new(&opts, &mut progress);
+        let len = segments * stride;
+        assert_eq!(copy_sparse(&infd, &outfd, &opts, len, &mut state).unwrap(), len);
+        assert_eq!(state.stats.data_segments, segments);
+        assert_eq!(state.stats.bytes_copied + state.stats.holes_skipped, len);
+        // The one buffer was kept between the segments.
+        assert_eq!(state.buf.len(), 8192);
+
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+        assert!(is_sparse(&File::open(&to).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_copy_attributes() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "{}", "source").unwrap();
+            fd.set_permissions(fs::Permissions::from_mode(0o640)).unwrap();
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", "destination").unwrap();
+        }
+
+        let times = [
+            libc::timespec { tv_sec: 1_000_000_000, tv_nsec: 123 },
+            libc::timespec { tv_sec: 1_200_000_000, tv_nsec: 456 },
+        ];
+        let path = CString::new(from.as_os_str().as_bytes()).unwrap();
+        cvt(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) })
+            .unwrap();
+
+        let infd = File::open(&from).unwrap();
+        let outfd = OpenOptions::new().write(true).open(&to).unwrap();
+        let opts = CopyOptions { preserve_times: true, ..CopyOptions::default() };
+        copy_attributes(&infd, &outfd, &opts).unwrap();
+
+        let meta = to.metadata().unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        assert_eq!((meta.st_atime(), meta.st_atime_nsec()), (1_000_000_000, 123));
+        assert_eq!((meta.st_mtime(), meta.st_mtime_nsec()), (1_200_000_000, 456));
+        // The data is left alone.
+        assert_eq!(read(&to).unwrap(), b"destination");
+    }
+
+    fn test_copy_sparse_growing(follow_growth: bool) {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let extra = "appended";
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        let original = read(&from).unwrap();
+
+        let infd = File::open(&from).unwrap();
+        let outfd = OpenOptions::new().read(true).write(true).create(true).open(&to).unwrap();
+        let opts = CopyOptions { follow_growth, ..CopyOptions::default() };
+
+        // Grow the source once the first segment has been copied.
+        let mut appended = false;
+        let mut progress = |_| {
+            if !appended {
+                let mut fd = OpenOptions::new().append(true).open(&from).unwrap();
+                write!(fd, "{}", extra).unwrap();
+                appended = true;
+            }
+        };
+        let total = copy_sparse(&infd, &outfd, &opts, len,
+                                &mut CopyState::new(&opts, &mut progress)).unwrap();
+
+        if follow_growth {
+            assert_eq!(total, len + extra.len() as u64);
+            assert_eq!(read(&to).unwrap(), read(&from).unwrap());
+        } else {
+            assert_eq!(total, len);
+            assert_eq!(read(&to).unwrap(), original);
+        }
+        assert_eq!(to.metadata().unwrap().len(), total);
+    }
+
+    #[test]
+    fn test_copy_read_only_source() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(b"read only").unwrap();
+            let times = [
+                libc::timespec { tv_sec: 1_000_000_000, tv_nsec: 0 },
+                libc::timespec { tv_sec: 1_100_000_000, tv_nsec: 789 },
+            ];
+            cvt(unsafe { libc::futimens(fd.as_raw_fd(), times.as_ptr()) }).unwrap();
+        }
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let opts = CopyOptions { preserve_times: true, ..CopyOptions::default() };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 9);
+
+        let meta = to.metadata().unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o444);
+        assert_eq!(meta.st_mtime(), 1_100_000_000);
+        assert_eq!(meta.st_mtime_nsec(), 789);
+        assert_eq!(read(&to).unwrap(), b"read only");
+    }
+
+    #[test]
+    fn test_copy_sparse_growing_clamped() {
+        test_copy_sparse_growing(false);
+    }
+
+    #[test]
+    fn test_copy_sparse_growing_followed() {
+        test_copy_sparse_growing(true);
+    }
+
+    #[test]
+    fn test_copy_special() {
+        // Creating device nodes needs privileges.
+        if unsafe { libc::geteuid() } != 0 {
+            return skip("needs root");
+        }
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let null = fs::metadata("/dev/null").unwrap();
+        let path = CString::new(from.as_os_str().as_bytes()).unwrap();
+        cvt(unsafe {
+            libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o604, null.st_rdev() as libc::dev_t)
+        }).unwrap();
+
+        let err = copy(&from, &to).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let opts = CopyOptions { copy_special: true, ..CopyOptions::default() };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 0);
+        let meta = fs::symlink_metadata(&to).unwrap();
+        assert!(meta.file_type().is_char_device());
+        assert_eq!(meta.st_rdev(), null.st_rdev());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o604);
+
+        // An existing destination is replaced unless no_clobber is set.
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), 0);
+        let opts = CopyOptions { no_clobber: true, ..opts };
+        let err = copy_with_options(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_copy_not_regular_file() {
+        let dir = tmpdir();
+        let to = dir.join("to.bin");
+
+        let err = copy(dir.path(), &to).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("the source is a directory"));
+
+        let err = copy(&dir.join("missing"), &to).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("the source path is not an existing regular file"));
+
+        let infd = File::open(dir.path()).unwrap();
+        let outfd = File::create(&to).unwrap();
+        let err = copy_fds(&infd, &infd.metadata().unwrap(), &outfd, &CopyOptions::default(),
+                           &mut |_, _| {}, None, None, None).unwrap_err();
+        assert_eq!(err.error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.error.to_string(), "the source is a directory");
+    }
+
+    #[test]
+    fn test_copy_drain_fifo() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let path = CString::new(from.as_os_str().as_bytes()).unwrap();
+        cvt(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }).unwrap();
+
+        let err = copy(&from, &to).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("the source is a FIFO"));
+
+        let data: Vec<u8> = (0..300 * 1024).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let fifo = from.clone();
+        let writer = thread::spawn(move || {
+            let mut out = OpenOptions::new().write(true).open(&fifo).unwrap();
+            for chunk in data.chunks(7000) {
+                out.write_all(chunk).unwrap();
+            }
+        });
+
+        let opts = CopyOptions { drain_fifo: true, ..CopyOptions::default() };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), expected.len() as u64);
+        writer.join().unwrap();
+        assert!(read(&to).unwrap() == expected);
+        assert!(fs::metadata(&from).unwrap().file_type().is_fifo());
+    }
+
+    #[test]
+    fn test_copy_max_output_bytes() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let len = create_sparse_with_data(&from, 0, 0);
+
+        // Sparse, but it's the logical length that's limited.
+        let opts = CopyOptions { max_output_bytes: Some(len - 1), ..CopyOptions::default() };
+        let err = copy_with_options(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(err.to_string().starts_with("output size limit exceeded"));
+        assert!(fs::symlink_metadata(&to).is_err());
+
+        let opts = CopyOptions { max_output_bytes: Some(len), ..opts };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), len);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+
+        // A FIFO's length isn't known until it's been read.
+        let fifo = dir.join("fifo");
+        let path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        cvt(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }).unwrap();
+        let writer = {
+            let fifo = fifo.clone();
+            thread::spawn(move || {
+                let mut out = OpenOptions::new().write(true).open(&fifo).unwrap();
+                // Fails once the reader gives up.
+                let _ = out.write_all(&[1; 200 * 1024]);
+            })
+        };
+        let opts = CopyOptions {
+            drain_fifo: true,
+            max_output_bytes: Some(100 * 1024),
+            ..CopyOptions::default()
+        };
+        let mut progress = |_, _| {};
+        let err = copy_file(&fifo, &to, &opts, &mut progress, None, None, None).unwrap_err();
+        writer.join().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(err.error.bytes_copied, 100 * 1024);
+    }
+
+    #[test]
+    fn test_copy_remove_on_error() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // Every write to /dev/full fails with ENOSPC. Only the link to it
+        // is removed, as removing a path never follows a symlink.
+        symlink("/dev/full", &to).unwrap();
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&vec![0x5a; 256 * 1024]).unwrap();
+        }
+
+        let err = copy(&from, &to).unwrap_err();
+        assert_eq!(err.error.raw_os_error(), Some(libc::ENOSPC));
+        assert!(to.symlink_metadata().is_ok());
+
+        let opts = CopyOptions { remove_on_error: true, ..CopyOptions::default() };
+        let err = copy_with_options(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.error.raw_os_error(), Some(libc::ENOSPC));
+        assert!(to.symlink_metadata().is_err());
+        assert!(Path::new("/dev/full").exists());
+    }
+
+    #[test]
+    fn test_kernel_copy_min_bytes() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let count = || {
+            let n = SYSCALLS.with(|counts| counts.get());
+            n.copy_file_range + n.sendfile
+        };
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&vec![0x5a; 4096]).unwrap();
+        }
+
+        // Not even probed for a small file.
+        let infd = File::open(&from).unwrap();
+        let outfd = File::create(&to).unwrap();
+        let opts = CopyOptions::default();
+        let before = count();
+        let mut progress = |_| {};
+        let mut state = CopyState::new(&opts, &mut progress);
+        assert_eq!(copy_range(&infd, &outfd, &opts, 4096, &mut state).unwrap(), 4096);
+        assert_eq!(count(), before);
+        assert!(!state.stats.used_kernel_copy);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+
+        let infd = File::open(&from).unwrap();
+        let outfd = File::create(&to).unwrap();
+        let opts = CopyOptions { kernel_copy_min_bytes: 0, ..CopyOptions::default() };
+        let mut state = CopyState::new(&opts, &mut progress);
+        assert_eq!(copy_range(&infd, &outfd, &opts, 4096, &mut state).unwrap(), 4096);
+        assert!(count() > before);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
+    #[test]
+    fn test_copy_to_open() {
+        use os::unix::fs::OpenOptionsExt;
+
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o640)).unwrap();
+        {
+            let mut fd = File::create(&to).unwrap();
+            fd.write_all(&vec![0x5a; len as usize + 4096]).unwrap();
+        }
+
+        let outfd = OpenOptions::new()
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOATIME)
+            .open(&to).unwrap();
+        assert_eq!(copy_to_open(&from, &outfd).unwrap(), len);
+
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+        assert_eq!(to.metadata().unwrap().permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn test_ensure_len() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+
+        let fd = File::create(&from).unwrap();
+        allocate_file(&fd, 100).unwrap();
+
+        ensure_len(&fd, 50).unwrap();
+        assert_eq!(fd.metadata().unwrap().len(), 100);
+        ensure_len(&fd, 200).unwrap();
+        assert_eq!(fd.metadata().unwrap().len(), 200);
+
+        allocate_file(&fd, 50).unwrap();
+        assert_eq!(fd.metadata().unwrap().len(), 50);
+    }
+
+    // Copy a sparse file in place over an existing file of `dest_len`.
+    fn test_copy_sparse_existing(dest_len: u64) {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        {
+            let mut fd = File::create(&to).unwrap();
+            fd.write_all(&vec![0x5a; dest_len as usize]).unwrap();
+        }
+
+        let infd = File::open(&from).unwrap();
+        let outfd = OpenOptions::new().write(true).open(&to).unwrap();
+        let opts = CopyOptions { no_truncate: true, ..CopyOptions::default() };
+        copy_sparse(&infd, &outfd, &opts, len, &mut CopyState::new(&opts, &mut |_| {}))
+            .unwrap();
+
+        // A longer tail is left for copy_fds() to trim.
+        assert_eq!(to.metadata().unwrap().len(), cmp::max(len, dest_len));
+        assert!(read(&to).unwrap()[..len as usize] == read(&from).unwrap()[..]);
+
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), len);
+        assert_eq!(to.metadata().unwrap().len(), len);
+        assert!(read(&to).unwrap() == read(&from).unwrap());
+    }
+
+    #[test]
+    fn test_copy_sparse_existing_shorter() {
+        test_copy_sparse_existing(4096);
+    }
+
+    #[test]
+    fn test_copy_sparse_existing_longer() {
+        test_copy_sparse_existing(4096 * 4096 + 64 * 1024);
+    }
+
+    #[test]
+    fn test_copy_method() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&vec![0x5a; 256 * 1024]).unwrap();
+        }
+
+        // As on a kernel with neither copy_file_range nor sendfile.
+        let opts = CopyOptions { force_uspace: true, ..CopyOptions::default() };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.method, CopyMethod::UserSpace);
+        assert!(!stats.used_kernel_copy);
+
+        let opts = CopyOptions { kernel_copy_min_bytes: 0, ..CopyOptions::default() };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.method == CopyMethod::UserSpace, !stats.used_kernel_copy);
+    }
+
+    #[test]
+    fn test_copy_attributes_errors() {
+        use os::unix::fs::OpenOptionsExt;
+
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        File::create(&from).unwrap();
+        File::create(&to).unwrap();
+
+        // An O_PATH descriptor can't be used to change the file's mode.
+        let infd = File::open(&from).unwrap();
+        let outfd = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(&to).unwrap();
+
+        let opts = CopyOptions { preserve_times: true, ..CopyOptions::default() };
+        let err = copy_attributes(&infd, &outfd, &opts).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        let opts = CopyOptions { ignore_perm_errors: true, ..opts };
+        copy_attributes(&infd, &outfd, &opts).unwrap();
+    }
+
+    #[test]
+    fn test_copy_into_at() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = "fragment";
+        let offset = 1000;
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "{}", data).unwrap();
+            let mut fd = File::create(&to).unwrap();
+            fd.write_all(&vec![0xaa; 64 * 1024]).unwrap();
+        }
+
+        let outfd = OpenOptions::new().write(true).open(&to).unwrap();
+        assert_eq!(copy_into_at(&from, &outfd, offset as u64).unwrap(), data.len() as u64);
+
+        let bytes = read(&to).unwrap();
+        assert_eq!(bytes.len(), 64 * 1024);
+        assert_eq!(&bytes[offset..offset + data.len()], data.as_bytes());
+        assert!(bytes[..offset].iter().all(|&b| b == 0xaa));
+        assert!(bytes[offset + data.len()..].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn test_copy_into_at_xmount() {
+        // Read and written where copy_file_range fails with EXDEV.
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let other = match other_mount(&dir, "into-at") {
+            Some(other) => other,
+            None => return skip("no other filesystem to copy to"),
+        };
+        let to = other.join("to.bin");
+        let data = vec![0x55; 300 * 1024];
+        fs::write(&from, &data).unwrap();
+        fs::write(&to, b"head").unwrap();
+
+        let outfd = OpenOptions::new().write(true).open(&to).unwrap();
+        let before = SYSCALLS.with(|c| c.get());
+        let result = copy_into_at(&from, &outfd, 4);
+        let after = SYSCALLS.with(|c| c.get());
+        let bytes = read(&to).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+
+        assert_eq!(result.unwrap(), data.len() as u64);
+        assert_eq!(&bytes[..4], b"head");
+        assert_eq!(&bytes[4..], &data[..]);
+        assert_eq!(after.read - before.read, 3);
+        assert_eq!(after.write - before.write, 3);
+    }
+
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(parse_kernel_version("3.10.0"), Some((3, 10)));
+        assert_eq!(parse_kernel_version("4.5.2"), Some((4, 5)));
+        assert_eq!(parse_kernel_version("5.15.0-generic"), Some((5, 15)));
+        assert_eq!(parse_kernel_version("6.1-rc3"), Some((6, 1)));
+        assert_eq!(parse_kernel_version("4"), None);
+        assert_eq!(parse_kernel_version("unknown"), None);
+
+        assert!(!release_supports_copy_file_range("3.10.0-1160.el7.x86_64"));
+        assert!(!release_supports_copy_file_range("4.4.0"));
+        assert!(release_supports_copy_file_range("4.5.2"));
+        // A later major version with an earlier minor one.
+        assert!(release_supports_copy_file_range("5.3.0"));
+        assert!(release_supports_copy_file_range("5.15.0-generic"));
+        assert!(release_supports_copy_file_range("unknown"));
+    }
+
+    #[test]
+    fn test_seed_from_env() {
+        static SEEDED: AtomicU8 = AtomicU8::new(SYSCALL_UNKNOWN);
+        static UNSEEDED: AtomicU8 = AtomicU8::new(SYSCALL_UNKNOWN);
+
+        seed_from_env(&UNSEEDED, None);
+        seed_from_env(&UNSEEDED, Some("0"));
+        seed_from_env(&SEEDED, Some("1"));
+
+        assert_eq!(UNSEEDED.load(Ordering::Relaxed), SYSCALL_UNKNOWN);
+        assert_eq!(SEEDED.load(Ordering::Relaxed), SYSCALL_UNAVAILABLE);
+
+        let mut attempted = false;
+        assert!(try_copy_syscall(&SEEDED, &[libc::ENOSYS], || {
+            attempted = true;
+            Ok(0)
+        }).is_none());
+        assert!(!attempted);
+    }
+
+    #[test]
+    fn test_copy_hashed() {
+        use collections::hash_map::DefaultHasher;
+
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 1024, 4096);
+        let mut hasher = DefaultHasher::new();
+        let (written, hash) = copy_hashed(&from, &to, &mut hasher).unwrap();
+
+        let mut expected = DefaultHasher::new();
+        expected.write(&read(&from).unwrap());
+        assert_eq!(written, len);
+        assert_eq!(hash, expected.finish());
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+        assert!(is_fsparse(&to).unwrap());
+    }
+
+    #[test]
+    fn test_next_sparse_segments_eof() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // Data running up to EOF.
+        let len = create_sparse_with_data(&from, 0, 0);
+        let fd = File::open(&from).unwrap();
+        assert_eq!(next_sparse_segments(&fd, 4096 * 4096, len).unwrap(), (4096 * 4096, len));
+        let last = *allocated_extents(&from).last().unwrap();
+        assert_eq!(last.1, len);
+
+        // A trailing hole.
+        let len = create_sparse_with_data(&to, 0, 1024 * 1024);
+        let fd = File::open(&to).unwrap();
+        let last = *allocated_extents(&to).last().unwrap();
+        assert!(last.1 < len);
+        assert_eq!(next_sparse_segments(&fd, last.1, len).unwrap(), (len, len));
+        assert_eq!(next_sparse_segments(&fd, last.0, len).unwrap(), last);
+    }
+
+    #[test]
+    fn test_copy_tee() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let other = dir.join("other.bin");
+
+        let len = create_sparse_with_data(&from, 1024, 1024);
+        {
+            let first = File::create(&to).unwrap();
+            let second = File::create(&other).unwrap();
+            assert_eq!(copy_tee(&from, &[&first, &second]).unwrap(), len);
+        }
+        let data = read(&from).unwrap();
+        assert!(read(&to).unwrap() == data);
+        assert!(read(&other).unwrap() == data);
+
+        // The failing destination is identified.
+        let first = File::create(&to).unwrap();
+        let second = File::open(&other).unwrap();
+        let err = copy_tee(&from, &[&first, &second]).unwrap_err();
+        assert_eq!(err.index, Some(1));
+        assert_eq!(err.error.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn test_first_data_offset() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        create_sparse_with_data(&from, 1024 * 1024, 0);
+        let fd = File::open(&from).unwrap();
+        assert_eq!(fd.first_data_offset().unwrap(), Some(1024 * 1024));
+
+        create_sparse(&to, 1024 * 1024);
+        let fd = File::open(&to).unwrap();
+        assert_eq!(fd.first_data_offset().unwrap(), None);
+    }
+
+    #[test]
+    fn test_offset_bounds() {
+        assert_eq!(Offset::new(0).unwrap().raw(), 0);
+        let max = i64::max_value() as u64;
+        assert_eq!(Offset::new(max).unwrap().raw(), i64::max_value());
+        assert_eq!(Offset::new(max + 1).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(Offset::new(u64::max_value()).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        assert_eq!(Offset::new(max - 1).unwrap().add(1).unwrap().get(), max);
+        assert_eq!(Offset::new(max).unwrap().add(1).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+        assert_eq!(Offset::new(1).unwrap().add(u64::max_value()).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+        assert_eq!(Offset::from_raw(i64::max_value()).get(), max);
+    }
+
+    #[test]
+    fn test_lseek_out_of_range() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let len = create_sparse_with_data(&from, 0, 0);
+        let fd = File::open(&from).unwrap();
+
+        let huge = i64::max_value() as u64 + 4096;
+        assert_eq!(lseek(&fd, huge, Wence::Set).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(next_sparse_segments(&fd, huge, len).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+        assert_eq!(lseek(&fd, i64::max_value() as u64, Wence::Data).unwrap(), SeekOff::EOF);
+    }
+
+    #[test]
+    fn test_copy_file_range_unsupported_here() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&vec![0x5a; 128 * 1024]).unwrap();
+        }
+
+        // copy_file_range fails with EBADF for an O_APPEND destination.
+        let infd = File::open(&from).unwrap();
+        let outfd = OpenOptions::new().append(true).create(true).open(&to).unwrap();
+        let opts = CopyOptions { block_size: Some(4096), ..CopyOptions::default() };
+        let mut progress = |_| {};
+        let mut state = CopyState::new(&opts, &mut progress);
+        let before = HAS_COPY_FILE_RANGE.load(Ordering::Relaxed);
+        let sendfile_before = HAS_SENDFILE.load(Ordering::Relaxed);
+
+        copy_range(&infd, &outfd, &opts, 128 * 1024, &mut state).unwrap();
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+        if before != SYSCALL_UNAVAILABLE {
+            assert!(state.cfr_unusable);
+            // Other copies are unaffected.
+            assert_ne!(HAS_COPY_FILE_RANGE.load(Ordering::Relaxed), SYSCALL_UNAVAILABLE);
+        }
+        // Nor can sendfile write to it, failing with EINVAL.
+        if sendfile_before != SYSCALL_UNAVAILABLE {
+            assert!(state.sendfile_unusable);
+            assert_ne!(HAS_SENDFILE.load(Ordering::Relaxed), SYSCALL_UNAVAILABLE);
+        }
+    }
+
+    #[test]
+    fn test_copy_setuid() {
+        // Only root can give the copy another owner and keep the bits.
+        if unsafe { libc::geteuid() } != 0 {
+            return skip("needs root");
+        }
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "{}", "#!/bin/sh").unwrap();
+            cvt(unsafe { libc::fchown(fd.as_raw_fd(), 1, 1) }).unwrap();
+        }
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o6755)).unwrap();
+
+        let opts = CopyOptions { preserve_owner: true, ..CopyOptions::default() };
+        copy_with_options(&from, &to, &opts).unwrap();
+
+        let meta = to.metadata().unwrap();
+        assert_eq!((meta.st_uid(), meta.st_gid()), (1, 1));
+        assert_eq!(meta.st_mode() & 0o7777, 0o6755);
+    }
+
+    #[test]
+    fn test_copy_reader_sparse() {
+        let dir = tmpdir();
+        let (_, to) = tmps(&dir);
+
+        let mut data = vec![0; 4 * 1024 * 1024];
+        data[..11].copy_from_slice(b"some header");
+        data[2 * 1024 * 1024..][..9].copy_from_slice(b"some data");
+        // Ending in a hole.
+        assert_eq!(copy_reader_sparse(&mut &data[..], &to).unwrap(), data.len() as u64);
+        assert!(read(&to).unwrap() == data);
+        assert!(is_fsparse(&to).unwrap());
+    }
+
+    #[test]
+    fn test_copy_deadline() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let len = 8 * 1024 * 1024;
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&vec![0x5a; len]).unwrap();
+        }
+
+        // Throttled to take a few seconds.
+        let opts = CopyOptions {
+            max_bytes_per_sec: Some(2 * 1024 * 1024),
+            deadline: Some(Instant::now() + Duration::from_millis(100)),
+            ..CopyOptions::default()
+        };
+        let start = Instant::now();
+        let err = copy_with_options(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(err.bytes_copied < len as u64);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 0);
+        let opts = CopyOptions { dry_run: true, ..CopyOptions::default() };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert!(!to.exists());
+
+        let segments = sparse_segments(&File::open(&from).unwrap()).unwrap();
+        let data = segments.iter().map(|&(start, end)| end - start).sum::<u64>();
+        assert_eq!(stats.bytes_total, len);
+        assert_eq!(stats.bytes_copied, data);
+        assert_eq!(stats.holes_skipped, len - data);
+        assert_eq!(stats.data_segments, segments.len() as u64);
+        assert!(stats.used_kernel_copy);
+
+        let copied = copy_stats(&from, &to).unwrap();
+        assert_eq!(copied.bytes_total, stats.bytes_total);
+
+        // An existing destination is left alone.
+        {
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", "untouched").unwrap();
+        }
+        copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(read(&to).unwrap(), b"untouched");
+    }
+
+    #[test]
+    fn test_copy_range_sparse_kernel() {
+        test_copy_range(false);
+    }
+
+    #[test]
+    fn test_copy_range_sparse_uspace() {
+        test_copy_range(true);
+    }
+
+    #[test]
+    fn test_copy_file_range_probe_shared() {
+        test_copy_range(false);
+        assert_ne!(HAS_COPY_FILE_RANGE.load(Ordering::Relaxed), SYSCALL_UNKNOWN);
+
+        let state = HAS_COPY_FILE_RANGE.load(Ordering::Relaxed);
+        thread::spawn(move || {
+            assert_eq!(HAS_COPY_FILE_RANGE.load(Ordering::Relaxed), state);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn test_sparse_copy_middle() {
+        if !kernel_supports_copy_file_range() {
+            return skip("no copy_file_range in this kernel");
+        }
+
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = "test data";
+
+        {
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        create_sparse(&from, 1024*1024);
+
+        let offset = 512*1024;
+        {
+            let infd = File::open(&to).unwrap();
+            let outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&from).unwrap();
+            let mut offdat: i64 = 512*1024;
+            let offptr = &mut offdat as *mut i64;
+            cvt(
+                unsafe {
+                    copy_file_range(
+                        infd.as_raw_fd(),
+                        ptr::null_mut(),
+                        outfd.as_raw_fd(),
+                        offptr,
+                        data.len(),
+                        0) as i64
+                }).unwrap();
+        }
+
+        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+
+        let bytes = read(&from).unwrap();
+        assert!(bytes.len() == 1024*1024);
+        assert!(bytes[offset] == b't');
+        assert!(bytes[offset+1] == b'e');
+        assert!(bytes[offset+2] == b's');
+        assert!(bytes[offset+3] == b't');
+        assert!(bytes[offset+data.len()] == 0);
+    }
+
+    #[test]
+    fn test_copy_file_range_at() {
+        if !kernel_supports_copy_file_range() {
+            return skip("no copy_file_range in this kernel");
+        }
+
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = "test data";
+        let offset = 512*1024;
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "XX{}", data).unwrap();
+        }
+
+        create_sparse(&to, 1024*1024);
+
+        {
+            let mut infd = File::open(&from).unwrap();
+            let mut outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&to).unwrap();
+
+            let written = copy_file_range_at(&infd, &outfd, 2, offset,
+                                             data.len() as u64).unwrap();
+            assert_eq!(written, data.len() as u64);
+
+            // Cursors are unaffected.
+            assert_eq!(infd.seek(SeekFrom::Current(0)).unwrap(), 0);
+            assert_eq!(outfd.seek(SeekFrom::Current(0)).unwrap(), 0);
+
+            // Short copy at EOF.
+            let written = copy_file_range_at(&infd, &outfd, 4, 0, 1024).unwrap();
+            assert_eq!(written, data.len() as u64 - 2);
+        }
+
+        let bytes = read(&to).unwrap();
+        assert_eq!(bytes.len(), 1024*1024);
+        assert_eq!(&bytes[offset as usize..offset as usize + data.len()], data.as_bytes());
+        assert_eq!(&bytes[..data.len() - 2], &data.as_bytes()[2..]);
+        assert!(is_sparse(&File::open(&to).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_lseek_data() {
+        if !kernel_supports_copy_file_range() {
+            return skip("kernel older than 4.5");
+        }
+
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = "test data";
+        let offset = 512*1024;
+
+        {
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        create_sparse(&from, 1024*1024);
+
+        {
+            let infd = File::open(&to).unwrap();
+            let outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&from).unwrap();
+            cvt(
+                unsafe {
+                    copy_file_range(
+                        infd.as_raw_fd(),
+                        ptr::null_mut(),
+                        outfd.as_raw_fd(),
+                        &mut (offset as i64) as *mut i64,
+                        data.len(),
+                        0) as i64
+                }).unwrap();
+        }
+
+        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+
+        let off = lseek(&File::open(&from).unwrap(), 0, Wence::Data).unwrap();
+        assert_eq!(off, SeekOff::Offset(offset));
+    }
+
+    #[test]
+    fn test_sparse_rust_seek() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 10);
+        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+
+        let bytes = read(&from).unwrap();
+        assert!(bytes.len() == len as usize);
+
+        let offset = 1024 * 4096;
+        assert!(bytes[offset] == b'c');
+        assert!(bytes[offset+1] == b'0');
+        assert!(bytes[offset+2] == b'0');
+        assert!(bytes[offset+3] == b'l');
+    }
+
+
+    #[test]
+    fn test_copy_file_range_safe() {
+        if !kernel_supports_copy_file_range() {
+            return skip("no copy_file_range in this kernel");
+        }
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        fs::write(&from, b"0123456789").unwrap();
+        let infd = File::open(&from).unwrap();
+        let outfd = OpenOptions::new().read(true).write(true).create(true).open(&to).unwrap();
+
+        // At explicit offsets, leaving the cursors alone.
+        assert_eq!(copy_file_range_safe(&infd, Some(4), &outfd, Some(2), 3).unwrap(), 3);
+        assert_eq!(read(&to).unwrap(), b"\0\0456");
+        assert_eq!(cursor(&infd).unwrap(), 0);
+        assert_eq!(cursor(&outfd).unwrap(), 0);
+
+        // From and advancing the cursors.
+        lseek(&infd, 7, Wence::Set).unwrap();
+        assert_eq!(copy_file_range_safe(&infd, None, &outfd, None, 2).unwrap(), 2);
+        assert_eq!(cursor(&infd).unwrap(), 9);
+        assert_eq!(cursor(&outfd).unwrap(), 2);
+        assert_eq!(read(&to).unwrap(), b"78456");
+
+        // Short at the end of the source, then nothing.
+        assert_eq!(copy_file_range_safe(&infd, None, &outfd, None, 100).unwrap(), 1);
+        assert_eq!(copy_file_range_safe(&infd, None, &outfd, None, 100).unwrap(), 0);
+
+        let huge = i64::max_value() as u64 + 1;
+        assert_eq!(copy_file_range_safe(&infd, Some(huge), &outfd, None, 1).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_lseek_no_data() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        create_sparse(&from, 1024*1024);
+
+        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+
+        let fd = File::open(&from).unwrap();
+        let off = lseek(&fd, 0, Wence::Data).unwrap();
+        assert!(off == SeekOff::EOF);
+    }
+
+    #[test]
+    fn test_sparse_segments() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 1024*1024);
+        let segments = sparse_segments(&File::open(&from).unwrap()).unwrap();
+        assert_eq!(segments.len(), 3);
+        for (&(start, end), &off) in segments.iter().zip(&[0, 1024*4096, 4096*4096]) {
+            assert!(start <= off && off + 8 <= end);
+        }
+        assert!(segments.last().unwrap().1 < len);
+
+        create_sparse(&to, 1024*1024);
+        assert!(sparse_segments(&File::open(&to).unwrap()).unwrap().is_empty());
+
+        {
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", "test data").unwrap();
+        }
+        assert_eq!(sparse_segments(&File::open(&to).unwrap()).unwrap(), vec![(0, 9)]);
+    }
+
+    #[test]
+    fn test_lseek_signalled() {
+        let _handler = SigcontHandler::install();
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        create_sparse_with_data(&from, 0, 0);
+        let fd = File::open(&from).unwrap();
+        let expected = sparse_segments(&fd).unwrap();
+
+        let target = unsafe { libc::pthread_self() };
+        let done = Arc::new(AtomicBool::new(false));
+        let signaller = {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    unsafe { libc::pthread_kill(target, libc::SIGCONT) };
+                    thread::yield_now();
+                }
+            })
+        };
+        let walks: Vec<_> = (0..1000).map(|_| sparse_segments(&fd)).collect();
+        done.store(true, Ordering::Relaxed);
+        signaller.join().unwrap();
+
+        for walk in walks {
+            assert_eq!(walk.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_fiemap() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+
+        let len = create_sparse_with_data(&from, 0, 1024*1024);
+        let fd = File::open(&from).unwrap();
+        let extents = fiemap(&fd).unwrap();
+        assert!(!extents.is_empty());
+        assert!(extents.windows(2).all(|w| w[0].logical + w[0].length <= w[1].logical));
+
+        // The map agrees with seeking about where the data is.
+        let mut mapped = Vec::new();
+        let mut pos = 0;
+        loop {
+            let (data, hole) = next_mapped_segments(&extents, pos, len);
+            if data >= len {
+                break;
+            }
+            mapped.push((data, hole));
+            pos = hole;
+        }
+        assert_eq!(mapped, sparse_segments(&fd).unwrap());
+    }
+
+    #[test]
+    fn test_copy_use_fiemap() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // Fully allocated, so not sparse, but mostly never written.
+        let len = 8 * 1024 * 1024;
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        {
+            let fd = File::create(&from).unwrap();
+            preallocate_file(&fd, len).unwrap();
+            fd.write_at(&data, 1024 * 1024).unwrap();
+        }
+        assert!(!super::is_sparse(&from).unwrap());
+
+        let opts = CopyOptions { use_fiemap: true, ..CopyOptions::default() };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, len);
+        assert_eq!(stats.data_segments, 1);
+        assert_eq!(stats.bytes_copied, data.len() as u64);
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+
+        // tmpfs can't map extents, so a source there is found by seeking.
+        let shm = Path::new("/dev/shm");
+        if shm.is_dir() {
+            let tmpfs = shm.join(format!("rust-fiemap-{}", process::id()));
+            copy(&from, &tmpfs).unwrap();
+            let err = fiemap(&File::open(&tmpfs).unwrap()).unwrap_err();
+            assert!(err.raw_os_error() == Some(libc::EOPNOTSUPP) ||
+                    err.raw_os_error() == Some(libc::ENOTTY));
+            assert_eq!(copy_with_options(&tmpfs, &to, &opts).unwrap(), len);
+            assert!(read(&from).unwrap() == read(&to).unwrap());
+            fs::remove_file(&tmpfs).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_allocate_file_is_sparse() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let len = 32 * 1024 * 1024;
+
+        {
+            let fd = File::create(&from).unwrap();
+            allocate_file(&fd, len).unwrap();
+        }
+
+        {
+            let fd = File::open(&from).unwrap();
+            assert_eq!(len, fd.metadata().unwrap().len());
+            assert!(is_sparse(&fd).unwrap());
+        }
+    }
+
+
+    #[test]
+    fn test_copy_bytes_uspace_small() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = "test data";
+        let offset = 32;
+
+        {
+            let mut fd = File::create(&to).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        create_sparse(&from, 128);
+        create_sparse(&to, 128);
+
+        {
+            let mut fd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&from).unwrap();
+            fd.seek(SeekFrom::Start(offset)).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        {
+            let mut infd = File::open(&from).unwrap();
+            let mut outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&to).unwrap();
+            infd.seek(SeekFrom::Start(offset)).unwrap();
+            outfd.seek(SeekFrom::Start(offset)).unwrap();
+
+            let opts = CopyOptions::default();
+            let written = copy_bytes_uspace(&infd, &outfd, data.len(), &opts,
+                                            &mut CopyState::new(&opts, &mut |_| {})).unwrap();
+            assert_eq!(written, data.len() as u64);
+        }
+
+        {
+            let from_data = read(&from).unwrap();
+            let to_data = read(&to).unwrap();
+            assert_eq!(from_data, to_data);
+        }
+    }
+
+    #[test]
+    fn test_copy_fd_with_buffer() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        fs::write(&from, &data).unwrap();
+
+        let infd = File::open(&from).unwrap();
+        let outfd = File::create(&to).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(copy_fd_with_buffer(&infd, &outfd, &mut buf).unwrap(), data.len() as u64);
+        assert_eq!(read(&to).unwrap(), data);
+
+        // A length that isn't a multiple of the buffer, through the
+        // internal copy with its options.
+        let outfd = File::create(&to).unwrap();
+        lseek(&infd, 0, Wence::Set).unwrap();
+        let opts = CopyOptions::default();
+        let mut progress = |_| {};
+        let mut state = CopyState::new(&opts, &mut progress);
+        let written = copy_bytes_uspace_buf(&infd, &outfd, 999, &mut buf, &opts, &mut state);
+        assert_eq!(written.unwrap(), 999);
+        assert!(state.buf.is_empty());
+        assert_eq!(read(&to).unwrap(), &data[..999]);
+
+        let err = copy_fd_with_buffer(&infd, &outfd, &mut []).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_copy_bytes_uspace_short() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = "test data";
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        {
+            let infd = File::open(&from).unwrap();
+            let outfd = File::create(&to).unwrap();
+            let opts = CopyOptions::default();
+            let err = copy_bytes_uspace(&infd, &outfd, 1024, &opts,
+                                        &mut CopyState::new(&opts, &mut |_| {})).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        {
+            let infd = File::open(&from).unwrap();
+            let outfd = File::create(&to).unwrap();
+            let opts = CopyOptions { allow_short: true, ..CopyOptions::default() };
+            let written = copy_bytes_uspace(&infd, &outfd, 1024, &opts,
+                                            &mut CopyState::new(&opts, &mut |_| {})).unwrap();
+            assert_eq!(written, data.len() as u64);
+        }
+
+        assert_eq!(read(&to).unwrap(), data.as_bytes());
+    }
+
+    #[test]
+    fn test_copy_stream() {
+        let data: Vec<u8> = (0..MIN_USPACE_BLKSIZE * 2 + 17).map(|i| i as u8).collect();
+
+        let mut out = Vec::new();
+        let written = copy_stream(&mut &data[..], &mut out, data.len() as u64).unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(out, data);
+
+        let mut out = Vec::new();
+        let written = copy_stream(&mut &data[..], &mut out, 100).unwrap();
+        assert_eq!(written, 100);
+        assert_eq!(out, &data[..100]);
+
+        let mut out = Vec::new();
+        let err = copy_stream(&mut &data[..], &mut out, data.len() as u64 + 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_chunk_len() {
+        assert_eq!(chunk_len(0), 0);
+        assert_eq!(chunk_len(4096), 4096);
+        assert_eq!(chunk_len(MAX_CHUNK), MAX_CHUNK as usize);
+        assert_eq!(chunk_len(MAX_CHUNK + 1), MAX_CHUNK as usize);
+        assert_eq!(chunk_len(u32::max_value() as u64 + 1), MAX_CHUNK as usize);
+        assert_eq!(chunk_len(u64::max_value()), MAX_CHUNK as usize);
+        assert!(MAX_CHUNK <= u32::max_value() as u64);
+    }
+
+    #[test]
+    fn test_copy_bytes_sendfile() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let size = 128*1024;
+        let data = iter::repeat("X").take(size).collect::<String>();
+
+        {
+            let mut fd: File = File::create(&from).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        {
+            let infd = File::open(&from).unwrap();
+            let outfd = File::create(&to).unwrap();
+            let mut written = 0;
+            while written < size as u64 {
+                written += copy_bytes_sendfile(&infd, &outfd,
+                                               size - written as usize).unwrap();
+            }
+            assert_eq!(written, size as u64);
+        }
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
+
+    #[test]
+    fn test_copy_interrupted() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let size = 64 * 1024 * 1024;
+
+        {
+            let mut fd: File = File::create(&from).unwrap();
+            let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            fd.write_all(&data).unwrap();
+        }
+
+        let _handler = SigcontHandler::install();
+        let done = Arc::new(AtomicBool::new(false));
+        let target = unsafe { libc::pthread_self() };
+        let signaller = {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    unsafe { libc::pthread_kill(target, libc::SIGCONT) };
+                    thread::yield_now();
+                }
+            })
+        };
+
+        let written = copy(&from, &to);
+        done.store(true, Ordering::Relaxed);
+        signaller.join().unwrap();
+
+        assert_eq!(written.unwrap(), size as u64);
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert!(from_data == to_data);
+    }
+
+    #[test]
+    fn test_copy_bytes_uspace_large() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let size = 128*1024;
+        let data = iter::repeat("X").take(size).collect::<String>();
+
+        {
+            let mut fd: File = File::create(&from).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        {
+            let infd = File::open(&from).unwrap();
+            let outfd = File::create(&to).unwrap();
+            let opts = CopyOptions::default();
+            let written = copy_bytes_uspace(&infd, &outfd, size, &opts,
+                                            &mut CopyState::new(&opts, &mut |_| {})).unwrap();
+
+            assert_eq!(written, size as u64);
+        }
+
+        assert_eq!(from.metadata().unwrap().len(),
+                   to.metadata().unwrap().len());
+
+        {
+            let from_data = read(&from).unwrap();
+            let to_data = read(&to).unwrap();
+            assert_eq!(from_data, to_data);
+        }
+    }
+
+
+
+
+    #[test]
+    fn test_simple_copy() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let text = "This is a test file.";
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+
+        let written = copy(&from, &to).unwrap();
+        assert_eq!(text.len() as u64, written);
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
+
+    #[test]
+    fn test_copy_onto_self() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let text = "This is a test file.";
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+
+        let err = copy(&from, &from).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let link = dir.path().join("link.bin");
+        symlink(&from, &link).unwrap();
+        let err = copy(&from, &link).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        assert_eq!(read(&from).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn test_copy_no_truncate() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let text = "This is a test file.";
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+        {
+            let mut file = File::create(&to).unwrap();
+            file.write_all(&[b'X'; 64 * 1024]).unwrap();
+        }
+
+        let opts = CopyOptions { no_truncate: true, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, text.len() as u64);
+        assert_eq!(to.metadata().unwrap().len(), text.len() as u64);
+        assert_eq!(read(&to).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn test_sparse_no_truncate() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let slen = create_sparse_with_data(&from, 1024, 1024);
+        {
+            let mut file = File::create(&to).unwrap();
+            let junk = vec![b'X'; 1024 * 1024];
+            for _ in 0..(slen / junk.len() as u64) + 2 {
+                file.write_all(&junk).unwrap();
+            }
+        }
+
+        let opts = CopyOptions { no_truncate: true, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, slen);
+        assert_eq!(to.metadata().unwrap().len(), slen);
+        assert!(is_fsparse(&to).unwrap());
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert!(from_data == to_data);
+    }
+
+    #[test]
+    fn test_copy_no_clobber() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let text = "This is a test file.";
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+
+        let opts = CopyOptions { no_clobber: true, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, text.len() as u64);
+        assert_eq!(read(&to).unwrap(), text.as_bytes());
+
+        {
+            let file = File::create(&to).unwrap();
+            write!(&file, "{}", "existing").unwrap();
+        }
+
+        let err = copy_with_options(&from, &to, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(read(&to).unwrap(), b"existing");
+    }
+
+    #[test]
+    fn test_copy_punch_zeros() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let size = 1024 * 1024;
 
-        let offset = 512*1024;
         {
-            let infd = File::open(&to).unwrap();
-            let outfd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&from).unwrap();
-            let mut offdat: i64 = 512*1024;
-            let offptr = &mut offdat as *mut i64;
-            cvt(
-                unsafe {
-                    copy_file_range(
-                        infd.as_raw_fd(),
-                        ptr::null_mut(),
-                        outfd.as_raw_fd(),
-                        offptr,
-                        data.len(),
-                        0) as i64
-                }).unwrap();
+            let mut file = File::create(&from).unwrap();
+            let mut data = vec![0; size];
+            data[size / 2] = b'X';
+            file.write_all(&data).unwrap();
         }
+        assert!(!is_fsparse(&from).unwrap());
 
-        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+        let opts = CopyOptions { punch_zeros: true, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, size as u64);
+        assert_eq!(to.metadata().unwrap().len(), size as u64);
+        assert!(to.metadata().unwrap().st_blocks() < from.metadata().unwrap().st_blocks());
 
-        let bytes = read(&from).unwrap();
-        assert!(bytes.len() == 1024*1024);
-        assert!(bytes[offset] == b't');
-        assert!(bytes[offset+1] == b'e');
-        assert!(bytes[offset+2] == b's');
-        assert!(bytes[offset+3] == b't');
-        assert!(bytes[offset+data.len()] == 0);
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert!(from_data == to_data);
+    }
+
+    #[test]
+    fn test_copy_hole_granularity() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        // A 32k run of zeroes, then a 128k one, between data.
+        let k = 1024;
+        let mut data = vec![1; 320 * k];
+        data[64 * k..96 * k].iter_mut().for_each(|b| *b = 0);
+        data[128 * k..256 * k].iter_mut().for_each(|b| *b = 0);
+        fs::write(&from, &data).unwrap();
+
+        let opts = CopyOptions {
+            punch_zeros: true,
+            block_size: Some(512 * k),
+            hole_granularity: Some(64 * k as u64),
+            ..CopyOptions::default()
+        };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), data.len() as u64);
+        assert!(read(&to).unwrap() == data);
+        let segments = sparse_segments(&File::open(&to).unwrap()).unwrap();
+        assert_eq!(segments, vec![(0, 128 * k as u64), (256 * k as u64, 320 * k as u64)]);
+
+        // Both runs are whole blocks of the destination.
+        let opts = CopyOptions { hole_granularity: None, ..opts };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), data.len() as u64);
+        assert!(read(&to).unwrap() == data);
+        assert_eq!(sparse_segments(&File::open(&to).unwrap()).unwrap().len(), 3);
+
+        // Longer than any run of zeroes.
+        let opts = CopyOptions { hole_granularity: Some(u64::max_value()), ..opts };
+        let result = copy_with_options(&from, &to, &opts);
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(result.unwrap(), data.len() as u64);
+            assert!(read(&to).unwrap() == data);
+            assert_eq!(sparse_segments(&File::open(&to).unwrap()).unwrap().len(), 1);
+        } else {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_copy_materialize_holes() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let len = create_sparse_with_data(&from, 0, 0);
+        assert!(super::is_sparse(&from).unwrap());
+
+        let opts = CopyOptions {
+            materialize_holes: true,
+            punch_zeros: true,
+            ..CopyOptions::default()
+        };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, len);
+        assert_eq!(stats.holes_skipped, 0);
+        assert_eq!(stats.method, CopyMethod::UserSpace);
+        assert!(!super::is_sparse(&to).unwrap());
+        assert!(to.metadata().unwrap().st_blocks() * 512 >= len);
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+    }
+
+    #[test]
+    fn test_copy_min_hole_len_ends() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let k = 1024;
+
+        // Short holes at the start, between the first two blocks of data
+        // and at the end, and a long one in the middle.
+        let len = 1024 * k + 40 * k;
+        {
+            let fd = File::create(&from).unwrap();
+            allocate_file(&fd, len).unwrap();
+            for &off in &[8 * k, 20 * k, 1024 * k + 24 * k] {
+                fd.write_at(&[1; 4096], off).unwrap();
+            }
+        }
+        assert_eq!(sparse_segments(&File::open(&from).unwrap()).unwrap().len(), 3);
+
+        // The kernel may allocate the short holes it copies, or not, so
+        // the layout is only certain when they're written from user-space.
+        let opts = CopyOptions {
+            min_hole_len: Some(16 * k),
+            force_uspace: true,
+            ..CopyOptions::default()
+        };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, len);
+        assert_eq!(stats.holes_skipped, 1024 * k);
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+        assert_eq!(sparse_segments(&File::open(&to).unwrap()).unwrap(),
+                   vec![(0, 24 * k), (1024 * k + 24 * k, len)]);
+    }
+
+    #[test]
+    fn test_copy_use_mmap() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let size = 8 * 1024 * 1024 + 123;
+        let data = (0..size).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+        File::create(&from).unwrap().write_all(&data).unwrap();
+
+        let opts = CopyOptions {
+            force_uspace: true,
+            use_mmap: true,
+            instrument: true,
+            ..CopyOptions::default()
+        };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_copied, size as u64);
+        assert_eq!(stats.syscalls.mmap, 1);
+        assert_eq!(stats.syscalls.read, 0);
+        assert!(read(&to).unwrap() == data);
+
+        // From an offset that isn't page-aligned.
+        {
+            let infd = File::open(&from).unwrap();
+            let outfd = File::create(&to).unwrap();
+            lseek(&infd, 1000, Wence::Set).unwrap();
+            let mut progress = |_| {};
+            let mut state = CopyState::new(&opts, &mut progress);
+            let written = copy_bytes_uspace(&infd, &outfd, size - 1000, &opts, &mut state);
+            assert_eq!(written.unwrap(), (size - 1000) as u64);
+            assert_eq!(cursor(&infd).unwrap(), size as u64);
+        }
+        assert!(read(&to).unwrap()[..] == data[1000..]);
+
+        // Small files are read as usual.
+        File::create(&from).unwrap().write_all(&data[..4096]).unwrap();
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.syscalls.mmap, 0);
+        assert!(read(&to).unwrap()[..] == data[..4096]);
+    }
+
+    #[test]
+    fn test_copy_tree() {
+        let dir = tmpdir();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+
+        fs::create_dir(&from).unwrap();
+        fs::create_dir(from.join("sub")).unwrap();
+        fs::create_dir(from.join("empty")).unwrap();
+        fs::set_permissions(from.join("empty"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let slen = create_sparse_with_data(&from.join("sub").join("sparse.bin"), 1024, 1024);
+        let text = "This is a test file.";
+        {
+            let file = File::create(from.join("file.txt")).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+        symlink("sub/sparse.bin", from.join("link")).unwrap();
+        // Would loop forever if followed.
+        symlink("..", from.join("sub").join("parent")).unwrap();
+
+        let total = copy_tree(&from, &to).unwrap();
+        assert_eq!(total, slen + text.len() as u64);
+
+        assert!(is_fsparse(&to.join("sub").join("sparse.bin")).unwrap());
+        assert_eq!(read(from.join("sub").join("sparse.bin")).unwrap(),
+                   read(to.join("sub").join("sparse.bin")).unwrap());
+        assert_eq!(read(to.join("file.txt")).unwrap(), text.as_bytes());
+
+        assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("sub/sparse.bin"));
+        assert_eq!(fs::read_link(to.join("sub").join("parent")).unwrap(), Path::new(".."));
+
+        let empty = fs::symlink_metadata(to.join("empty")).unwrap();
+        assert!(empty.is_dir());
+        assert_eq!(empty.permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::read_dir(to.join("empty")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_copy_tree_hard_links() {
+        let dir = tmpdir();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        let text = "This is a test file.";
+
+        fs::create_dir(&from).unwrap();
+        fs::create_dir(from.join("sub")).unwrap();
+        {
+            let file = File::create(from.join("a.txt")).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+        fs::hard_link(from.join("a.txt"), from.join("sub").join("b.txt")).unwrap();
+
+        // The data is only copied once.
+        assert_eq!(copy_tree(&from, &to).unwrap(), text.len() as u64);
+
+        let a = to.join("a.txt").metadata().unwrap();
+        let b = to.join("sub").join("b.txt").metadata().unwrap();
+        assert_eq!((a.st_dev(), a.st_ino()), (b.st_dev(), b.st_ino()));
+        assert_eq!(a.st_nlink(), 2);
+        assert_eq!(read(to.join("sub").join("b.txt")).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn test_copy_symlink_no_follow() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let target = dir.path().join("target.txt");
+        let text = "This is a test file.";
+
+        {
+            let file = File::create(&target).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+        symlink("target.txt", &from).unwrap();
+
+        let opts = CopyOptions { follow_symlinks: false, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, "target.txt".len() as u64);
+        assert_eq!(fs::read_link(&to).unwrap(), Path::new("target.txt"));
+        assert_eq!(read(&to).unwrap(), text.as_bytes());
+
+        // The default still copies the target's data.
+        let to2 = dir.path().join("to2.bin");
+        assert_eq!(copy(&from, &to2).unwrap(), text.len() as u64);
+        assert!(!fs::symlink_metadata(&to2).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn test_sparse_preallocate() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let slen = create_sparse_with_data(&from, 1024, 1024);
+
+        let opts = CopyOptions { preallocate: true, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, slen);
+
+        let meta = to.metadata().unwrap();
+        assert_eq!(meta.len(), slen);
+        assert!(meta.st_blocks() * 512 >= slen);
+        assert!(!is_fsparse(&to).unwrap());
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert!(from_data == to_data);
+    }
+
+    #[test]
+    fn test_copy_into_dir() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let sub = dir.path().join("sub");
+        let text = "This is a test file.";
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+        fs::create_dir(&sub).unwrap();
+
+        let written = copy(&from, &sub).unwrap();
+        assert_eq!(written, text.len() as u64);
+        assert_eq!(read(sub.join("from.bin")).unwrap(), text.as_bytes());
+
+        let err = copy(Path::new("/"), &sub).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // Onto itself via the directory.
+        let err = copy(&sub.join("from.bin"), &sub).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_sparse() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let slen = create_sparse_with_data(&from, 0, 0);
+        assert_eq!(slen, from.metadata().unwrap().len());
+        assert!(is_fsparse(&from).unwrap());
+
+        let written = copy(&from, &to).unwrap();
+        assert_eq!(slen, written);
+        assert!(is_fsparse(&to).unwrap());
+        assert_eq!(allocated_extents(&from), allocated_extents(&to));
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
+
+    #[test]
+    fn test_sparse_leading_gap() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let slen = create_sparse_with_data(&from, 1024, 0);
+        assert_eq!(slen, from.metadata().unwrap().len());
+        assert!(is_fsparse(&from).unwrap());
+
+        let written = copy(&from, &to).unwrap();
+        assert_eq!(slen, written);
+        assert!(is_fsparse(&to).unwrap());
+        assert_eq!(allocated_extents(&from), allocated_extents(&to));
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
+
+    #[test]
+    fn test_sparse_trailng_gap() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let slen = create_sparse_with_data(&from, 1024, 1024);
+        assert_eq!(slen, from.metadata().unwrap().len());
+        assert!(is_fsparse(&from).unwrap());
+
+        let written = copy(&from, &to).unwrap();
+        assert_eq!(slen, written);
+        assert!(is_fsparse(&to).unwrap());
+        assert_eq!(allocated_extents(&from), allocated_extents(&to));
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
+
+    #[test]
+    fn test_copy_with_options() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let slen = create_sparse_with_data(&from, 1024, 1024);
+
+        let opts = CopyOptions {
+            force_uspace: true,
+            preserve_sparse: false,
+            block_size: Some(64 * 1024),
+            ..CopyOptions::default()
+        };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(slen, written);
+        assert!(!is_fsparse(&to).unwrap());
+
+        let written = copy_with_options(&from, &to, &CopyOptions::default()).unwrap();
+        assert_eq!(slen, written);
+        assert!(is_fsparse(&to).unwrap());
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
+
+    #[test]
+    fn test_copy_preserve_owner() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", "owned").unwrap();
+        }
+
+        let is_root = unsafe { libc::geteuid() } == 0;
+        if is_root {
+            let path = CString::new(from.as_os_str().as_bytes()).unwrap();
+            cvt(unsafe { libc::chown(path.as_ptr(), 1, 1) }).unwrap();
+        }
+
+        let opts = CopyOptions { preserve_owner: true, ..CopyOptions::default() };
+        copy_with_options(&from, &to, &opts).unwrap();
+
+        if is_root {
+            let meta = to.metadata().unwrap();
+            assert_eq!(meta.st_uid(), 1);
+            assert_eq!(meta.st_gid(), 1);
+        }
+    }
+
+    #[test]
+    fn test_copy_xattrs() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let name = b"user.test\0";
+        let value = b"test value";
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", "attributed").unwrap();
+            let r = unsafe {
+                libc::fsetxattr(file.as_raw_fd(), name.as_ptr() as *const libc::c_char,
+                                value.as_ptr() as *const libc::c_void, value.len(), 0)
+            };
+            if r == -1 {
+                return skip("no user xattrs on this filesystem");
+            }
+        }
+
+        let opts = CopyOptions { preserve_xattrs: true, ..CopyOptions::default() };
+        copy_with_options(&from, &to, &opts).unwrap();
+
+        let outfd = File::open(&to).unwrap();
+        let copied = xattr_read(|buf, size| unsafe {
+            libc::fgetxattr(outfd.as_raw_fd(), name.as_ptr() as *const libc::c_char,
+                            buf, size)
+        }).unwrap();
+        assert_eq!(&copied[..], &value[..]);
+    }
+
+    #[test]
+    fn test_xattr_read_grows() {
+        // An empty value that grows to 5 bytes, then to 8, between the
+        // calls. The kernel answers a size of 0 with the current length.
+        let mut lens = vec![0, 5, 5, 8, 8, 8].into_iter();
+        let value = xattr_read(|buf, size| {
+            let len = lens.next().unwrap();
+            if size > 0 {
+                if len > size {
+                    unsafe { *libc::__errno_location() = libc::ERANGE };
+                    return -1;
+                }
+                unsafe { ptr::write_bytes(buf as *mut u8, 7, len) };
+            }
+            len as libc::ssize_t
+        }).unwrap();
+        assert_eq!(value, vec![7; 8]);
+        assert!(lens.next().is_none());
+    }
+
+    #[test]
+    fn test_copy_acl() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let name = b"system.posix_acl_access\0";
+
+        // user::rw-, user:1000:r--, group::r--, mask::rw-, other::---
+        let mut acl = vec![2, 0, 0, 0];
+        for &(tag, perm, id) in &[(0x01u16, 6u16, u32::max_value()), (0x02, 4, 1000),
+                                  (0x04, 4, u32::max_value()), (0x10, 6, u32::max_value()),
+                                  (0x20, 0, u32::max_value())] {
+            acl.extend_from_slice(&[tag as u8, (tag >> 8) as u8, perm as u8, (perm >> 8) as u8]);
+            acl.extend_from_slice(&[id as u8, (id >> 8) as u8, (id >> 16) as u8, (id >> 24) as u8]);
+        }
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", "controlled").unwrap();
+            let r = unsafe {
+                libc::fsetxattr(file.as_raw_fd(), name.as_ptr() as *const libc::c_char,
+                                acl.as_ptr() as *const libc::c_void, acl.len(), 0)
+            };
+            if r == -1 {
+                return skip("no ACLs on this filesystem");
+            }
+        }
+        let from_mode = from.metadata().unwrap().permissions().mode();
+        assert_eq!(from_mode & 0o777, 0o660);
+
+        let opts = CopyOptions { preserve_acl: true, ..CopyOptions::default() };
+        copy_with_options(&from, &to, &opts).unwrap();
+
+        let outfd = File::open(&to).unwrap();
+        let copied = xattr_read(|buf, size| unsafe {
+            libc::fgetxattr(outfd.as_raw_fd(), name.as_ptr() as *const libc::c_char,
+                            buf, size)
+        }).unwrap();
+        assert_eq!(copied, acl);
+        assert_eq!(to.metadata().unwrap().permissions().mode(), from_mode);
+
+        // A pipe can't hold an ACL, so it's left behind rather than
+        // failing, as on a filesystem with no xattrs.
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe(fds.as_mut_ptr()) }).unwrap();
+        let (_reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        copy_acls(&File::open(&from).unwrap(), &writer).unwrap();
+    }
+
+    #[test]
+    fn test_copy_create_parents() {
+        let dir = tmpdir();
+        let (from, _) = tmps(&dir);
+        let to = dir.path().join("a").join("b").join("to.bin");
+        let text = "This is a test file.";
+        fs::write(&from, text).unwrap();
+
+        let err = copy_ctx(&from, &to, &CopyOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.path.as_ref(), Some(&to));
+        let msg = format!("the destination directory {} does not exist",
+                          dir.path().join("a").join("b").display());
+        assert_eq!(err.error.error.to_string(), msg);
+        assert!(!dir.path().join("a").exists());
+
+        let opts = CopyOptions { create_parents: true, ..CopyOptions::default() };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), text.len() as u64);
+        assert_eq!(read(&to).unwrap(), text.as_bytes());
+        // Nothing to create the second time.
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), text.len() as u64);
+    }
+
+    #[test]
+    fn test_copy_presized() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let data = b"c00lc0d3";
+        let len = 1024 * 1024;
+
+        {
+            let fd = File::create(&from).unwrap();
+            allocate_file(&fd, len).unwrap();
+            fd.write_at(data, 0).unwrap();
+            fd.write_at(data, 512 * 1024).unwrap();
+        }
+        assert!(super::is_sparse(&from).unwrap());
+
+        // A container twice the size, already holding other bytes.
+        fs::write(&to, vec![0x55; 2 * len as usize]).unwrap();
+
+        let opts = CopyOptions { presized: true, ..CopyOptions::default() };
+        assert_eq!(copy_with_options(&from, &to, &opts).unwrap(), len);
+
+        // The data segments are whole blocks, the rest of them zeroes.
+        let source = read(&from).unwrap();
+        let mut expected = vec![0x55; 2 * len as usize];
+        let segments = sparse_segments(&File::open(&from).unwrap()).unwrap();
+        assert_eq!(segments.len(), 2);
+        for &(start, end) in &segments {
+            let (start, end) = (start as usize, end as usize);
+            expected[start..end].copy_from_slice(&source[start..end]);
+        }
+        let bytes = read(&to).unwrap();
+        assert_eq!(bytes.len(), expected.len());
+        assert!(bytes == expected);
+
+        let short = dir.path().join("short.bin");
+        fs::write(&short, vec![0x55; len as usize - 1]).unwrap();
+        let err = copy_with_options(&from, &short, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs::metadata(&short).unwrap().len(), len - 1);
+
+        // It isn't created if missing.
+        let missing = dir.path().join("missing.bin");
+        let err = copy_with_options(&from, &missing, &opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
     }
 
     #[test]
-    fn test_lseek_data() {
-        if !supported_kernel() {
-            return;
+    fn test_copy_inode_flags() {
+        // Setting append-only needs CAP_LINUX_IMMUTABLE.
+        if unsafe { libc::geteuid() } != 0 {
+            return skip("needs root");
+        }
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        fs::write(&from, "append only").unwrap();
+        let flags = |path: &Path| get_inode_flags(&File::open(path).unwrap()).unwrap();
+        match set_inode_flags(&File::open(&from).unwrap(), flags(&from) | FS_APPEND_FL) {
+            Err(ref e) if flags_unsupported(e) => return skip("no inode flags on this filesystem"),
+            result => result.unwrap(),
         }
+        // Other flags, e.g. ext4's extents flag, can't be cleared.
+        let clear = |path: &Path| {
+            set_inode_flags(&File::open(path).unwrap(), flags(path) & !FS_APPEND_FL).unwrap()
+        };
+
+        let opts = CopyOptions { preserve_flags: true, ..CopyOptions::default() };
+        let plain = copy_with_options(&from, &to, &opts).map(|_| flags(&to));
+        // Append-only files can't be removed, so clear them before checking.
+        clear(&to);
+        fs::remove_file(&to).unwrap();
+
+        // The temporary file must be renamed before it's made append-only.
+        let opts = CopyOptions { atomic: true, ..opts };
+        let atomic = copy_with_options(&from, &to, &opts).map(|_| flags(&to));
+        clear(&to);
+        clear(&from);
+
+        assert!(plain.unwrap() & FS_APPEND_FL != 0);
+        assert!(atomic.unwrap() & FS_APPEND_FL != 0);
+        assert_eq!(read(&to).unwrap(), b"append only");
+    }
 
+    #[test]
+    fn test_copy_stats() {
         let dir = tmpdir();
         let (from, to) = tmps(&dir);
-        let data = "test data";
-        let offset = 512*1024;
 
-        {
-            let mut fd = File::create(&to).unwrap();
-            write!(fd, "{}", data).unwrap();
-        }
+        let slen = create_sparse_with_data(&from, 1024, 1024);
 
-        create_sparse(&from, 1024*1024);
+        let stats = copy_stats(&from, &to).unwrap();
+        assert_eq!(stats.bytes_total, slen);
+        assert!(stats.holes_skipped > 0);
+        assert!(stats.bytes_copied < slen);
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
+
+    #[test]
+    fn test_copy_stats_dense() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let text = "This is a test file.";
 
         {
-            let infd = File::open(&to).unwrap();
-            let outfd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&from).unwrap();
-            cvt(
-                unsafe {
-                    copy_file_range(
-                        infd.as_raw_fd(),
-                        ptr::null_mut(),
-                        outfd.as_raw_fd(),
-                        &mut (offset as i64) as *mut i64,
-                        data.len(),
-                        0) as i64
-                }).unwrap();
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", text).unwrap();
         }
 
-        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
-
-        let off = lseek(&File::open(&from).unwrap(), 0, Wence::Data).unwrap();
-        assert_eq!(off, SeekOff::Offset(offset));
+        let stats = copy_stats(&from, &to).unwrap();
+        assert_eq!(stats.bytes_total, text.len() as u64);
+        assert_eq!(stats.bytes_copied, text.len() as u64);
+        assert_eq!(stats.holes_skipped, 0);
     }
 
     #[test]
-    fn test_sparse_rust_seek() {
+    fn test_copy_sync() {
         let dir = tmpdir();
-        let (from, _) = tmps(&dir);
+        let (from, to) = tmps(&dir);
 
-        let len = create_sparse_with_data(&from, 0, 10);
-        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+        let slen = create_sparse_with_data(&from, 1024, 1024);
 
-        let bytes = read(&from).unwrap();
-        assert!(bytes.len() == len as usize);
+        let opts = CopyOptions { sync: true, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(slen, written);
 
-        let offset = 1024 * 4096;
-        assert!(bytes[offset] == b'c');
-        assert!(bytes[offset+1] == b'0');
-        assert!(bytes[offset+2] == b'0');
-        assert!(bytes[offset+3] == b'l');
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
     }
 
-
     #[test]
-    fn test_lseek_no_data() {
+    fn test_copy_uspace_large_blocks() {
         let dir = tmpdir();
-        let (from, _) = tmps(&dir);
-        create_sparse(&from, 1024*1024);
+        let (from, to) = tmps(&dir);
+        let size = 16 * 1024 * 1024;
 
-        assert!(is_sparse(&File::open(&from).unwrap()).unwrap());
+        {
+            let mut fd: File = File::create(&from).unwrap();
+            let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            fd.write_all(&data).unwrap();
+        }
 
-        let fd = File::open(&from).unwrap();
-        let off = lseek(&fd, 0, Wence::Data).unwrap();
-        assert!(off == SeekOff::EOF);
+        let opts = CopyOptions {
+            force_uspace: true,
+            block_size: Some(1024 * 1024),
+            ..CopyOptions::default()
+        };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, size as u64);
+
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert!(from_data == to_data);
     }
 
     #[test]
-    fn test_allocate_file_is_sparse() {
+    fn test_copy_throttled() {
         let dir = tmpdir();
-        let (from, _) = tmps(&dir);
-        let len = 32 * 1024 * 1024;
+        let (from, to) = tmps(&dir);
+        let size = 256 * 1024;
 
         {
-            let fd = File::create(&from).unwrap();
-            allocate_file(&fd, len).unwrap();
+            let mut fd: File = File::create(&from).unwrap();
+            fd.write_all(&vec![b'X'; size]).unwrap();
         }
 
-        {
-            let fd = File::open(&from).unwrap();
-            assert_eq!(len, fd.metadata().unwrap().len());
-            assert!(is_sparse(&fd).unwrap());
-        }
-    }
+        let opts = CopyOptions {
+            max_bytes_per_sec: Some(512 * 1024),
+            block_size: Some(16 * 1024),
+            ..CopyOptions::default()
+        };
+        let start = Instant::now();
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, size as u64);
+        assert!(start.elapsed() >= Duration::from_millis(500));
 
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert!(from_data == to_data);
+    }
 
     #[test]
-    fn test_copy_bytes_uspace_small() {
+    fn test_copy_verify() {
         let dir = tmpdir();
         let (from, to) = tmps(&dir);
-        let data = "test data";
-        let offset = 32;
 
-        {
-            let mut fd = File::create(&to).unwrap();
-            write!(fd, "{}", data).unwrap();
-        }
+        let slen = create_sparse_with_data(&from, 1024, 1024);
 
-        create_sparse(&from, 128);
-        create_sparse(&to, 128);
+        let opts = CopyOptions { verify: true, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, slen);
 
+        // A dense copy verifies against the sparse source.
+        let dense = dir.path().join("dense.bin");
         {
-            let mut fd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&from).unwrap();
-            fd.seek(SeekFrom::Start(offset)).unwrap();
-            write!(fd, "{}", data).unwrap();
+            let mut fd = File::create(&dense).unwrap();
+            fd.write_all(&read(&from).unwrap()).unwrap();
         }
+        verify_copy(&File::open(&from).unwrap(), &File::open(&dense).unwrap(), 4096).unwrap();
 
         {
-            let mut infd = File::open(&from).unwrap();
-            let mut outfd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&to).unwrap();
-            infd.seek(SeekFrom::Start(offset)).unwrap();
-            outfd.seek(SeekFrom::Start(offset)).unwrap();
-
-            let written = copy_bytes_uspace(&infd, &outfd, data.len()).unwrap();
-            assert_eq!(written, data.len() as u64);
+            let mut fd = OpenOptions::new().write(true).open(&to).unwrap();
+            fd.seek(SeekFrom::Start(1024*4096)).unwrap();
+            write!(fd, "{}", "corrupt").unwrap();
         }
+        let err = verify_copy(&File::open(&from).unwrap(), &File::open(&to).unwrap(), 4096)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        allocate_file(&File::create(&dense).unwrap(), slen - 1).unwrap();
+        let err = verify_copy(&File::open(&from).unwrap(), &File::open(&dense).unwrap(), 4096)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_copy_range_truncated_kernel() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let size = 64 * 1024;
+        let cut = 16 * 1024;
 
         {
-            let from_data = read(&from).unwrap();
-            let to_data = read(&to).unwrap();
-            assert_eq!(from_data, to_data);
+            let mut fd: File = File::create(&from).unwrap();
+            fd.write_all(&vec![b'X'; size]).unwrap();
         }
+
+        let infd = File::open(&from).unwrap();
+        let outfd = File::create(&to).unwrap();
+        // As if truncated after the length to copy was read.
+        allocate_file(&OpenOptions::new().write(true).open(&from).unwrap(), cut).unwrap();
+
+        let opts = CopyOptions { kernel_copy_min_bytes: 0, ..CopyOptions::default() };
+        let mut progress = |_| {};
+        let mut state = CopyState::new(&opts, &mut progress);
+        let err = copy_range(&infd, &outfd, &opts, size as u64, &mut state).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(state.stats.bytes_copied, cut);
     }
 
     #[test]
-    fn test_copy_bytes_uspace_large() {
+    fn test_copy_error_bytes_copied() {
         let dir = tmpdir();
         let (from, to) = tmps(&dir);
-        let size = 128*1024;
-        let data = iter::repeat("X").take(size).collect::<String>();
+        let size = 64 * 1024;
+        let cut = 16 * 1024;
 
         {
             let mut fd: File = File::create(&from).unwrap();
-            write!(fd, "{}", data).unwrap();
+            fd.write_all(&vec![b'X'; size]).unwrap();
         }
 
-        {
-            let infd = File::open(&from).unwrap();
-            let outfd = File::create(&to).unwrap();
-            let written = copy_bytes_uspace(&infd, &outfd, size).unwrap();
+        // Shrink the source underneath the copy so that it fails part way.
+        let opts = CopyOptions {
+            force_uspace: true,
+            block_size: Some(4096),
+            ..CopyOptions::default()
+        };
+        let mut truncated = false;
+        let err = copy_file(&from, &to, &opts, &mut |_, _| {
+            if !truncated {
+                allocate_file(&OpenOptions::new().write(true).open(&from).unwrap(),
+                              cut).unwrap();
+                truncated = true;
+            }
+        }, None, None, None).unwrap_err();
 
-            assert_eq!(written, size as u64);
-        }
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.error.bytes_copied, cut);
+        assert_eq!(to.metadata().unwrap().len(), cut);
+    }
 
-        assert_eq!(from.metadata().unwrap().len(),
-                   to.metadata().unwrap().len());
+    #[test]
+    fn test_copy_fd() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+
+        let slen = create_sparse_with_data(&from, 1024, 1024);
 
         {
-            let from_data = read(&from).unwrap();
-            let to_data = read(&to).unwrap();
-            assert_eq!(from_data, to_data);
-        }
-    }
+            let infd = File::open(&from).unwrap();
+            let outfd = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(&to).unwrap();
+            let opts = CopyOptions { verify: true, ..CopyOptions::default() };
+            let written = copy_fd(&infd, &outfd, &opts).unwrap();
+            assert_eq!(written, slen);
 
+            let err = copy_fd(&outfd, &outfd, &opts).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
 
+        // Not a regular file, so it's neither probed for holes nor given
+        // any, and the holes are copied as zeroes.
+        let null = OpenOptions::new().write(true).open("/dev/null").unwrap();
+        assert_eq!(copy_fd(&File::open(&from).unwrap(), &null, &CopyOptions::default()).unwrap(),
+                   slen);
 
+        assert!(is_fsparse(&to).unwrap());
+        let from_data = read(&from).unwrap();
+        let to_data = read(&to).unwrap();
+        assert_eq!(from_data, to_data);
+    }
 
     #[test]
-    fn test_simple_copy() {
+    fn test_copy_unknown_flags() {
         let dir = tmpdir();
         let (from, to) = tmps(&dir);
         let text = "This is a test file.";
@@ -598,44 +6250,72 @@ mod tests {
             write!(&file, "{}", text).unwrap();
         }
 
-        let written = copy(&from, &to).unwrap();
-        assert_eq!(text.len() as u64, written);
+        let opts = CopyOptions { copy_flags: 0x8000_0000, ..CopyOptions::default() };
+        let written = copy_with_options(&from, &to, &opts).unwrap();
+        assert_eq!(written, text.len() as u64);
+        assert_eq!(read(&to).unwrap(), text.as_bytes());
+    }
 
-        let from_data = read(&from).unwrap();
-        let to_data = read(&to).unwrap();
-        assert_eq!(from_data, to_data);
+    #[test]
+    fn test_copy_progress() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let size = 128*1024;
+        let data = iter::repeat("X").take(size).collect::<String>();
+
+        {
+            let mut fd: File = File::create(&from).unwrap();
+            write!(fd, "{}", data).unwrap();
+        }
+
+        let mut calls = 0;
+        let mut last = 0;
+        let written = copy_with_progress(&from, &to, |copied, total| {
+            assert_eq!(total, size as u64);
+            assert!(copied >= last);
+            calls += 1;
+            last = copied;
+        }).unwrap();
+
+        assert_eq!(written, size as u64);
+        assert_eq!(last, size as u64);
+        assert!(calls > 0);
     }
 
     #[test]
-    fn test_sparse() {
+    fn test_sparse_progress() {
         let dir = tmpdir();
         let (from, to) = tmps(&dir);
 
-        let slen = create_sparse_with_data(&from, 0, 0);
-        assert_eq!(slen, from.metadata().unwrap().len());
-        assert!(is_fsparse(&from).unwrap());
+        let slen = create_sparse_with_data(&from, 1024, 1024);
 
-        let written = copy(&from, &to).unwrap();
-        assert_eq!(slen, written);
-        assert!(is_fsparse(&to).unwrap());
+        let mut last = 0;
+        let written = copy_with_progress(&from, &to, |copied, total| {
+            assert_eq!(total, slen);
+            assert!(copied >= last);
+            last = copied;
+        }).unwrap();
 
-        let from_data = read(&from).unwrap();
-        let to_data = read(&to).unwrap();
-        assert_eq!(from_data, to_data);
+        assert_eq!(written, slen);
+        assert_eq!(last, slen);
     }
 
     #[test]
-    fn test_sparse_leading_gap() {
+    fn test_reflink_copy() {
         let dir = tmpdir();
         let (from, to) = tmps(&dir);
 
-        let slen = create_sparse_with_data(&from, 1024, 0);
-        assert_eq!(slen, from.metadata().unwrap().len());
-        assert!(is_fsparse(&from).unwrap());
+        let slen = create_sparse_with_data(&from, 1024, 1024);
 
-        let written = copy(&from, &to).unwrap();
-        assert_eq!(slen, written);
-        assert!(is_fsparse(&to).unwrap());
+        {
+            let infd = File::open(&from).unwrap();
+            let outfd = File::create(&to).unwrap();
+            if !try_reflink(&infd, &outfd).unwrap() {
+                // Unsupported here; copy() must still succeed.
+                drop(outfd);
+                assert_eq!(copy(&from, &to).unwrap(), slen);
+            }
+        }
 
         let from_data = read(&from).unwrap();
         let to_data = read(&to).unwrap();
@@ -643,21 +6323,60 @@ mod tests {
     }
 
     #[test]
-    fn test_sparse_trailng_gap() {
+    fn test_clone_range() {
         let dir = tmpdir();
         let (from, to) = tmps(&dir);
+        let len = create_sparse_with_data(&from, 0, 0);
 
-        let slen = create_sparse_with_data(&from, 1024, 1024);
-        assert_eq!(slen, from.metadata().unwrap().len());
-        assert!(is_fsparse(&from).unwrap());
+        let infd = File::open(&from).unwrap();
+        if !on_btrfs(&infd).unwrap() {
+            // Elsewhere it may or may not work, but doesn't fail.
+            try_clone_range(&infd, 0, 4096, &File::create(&to).unwrap(), 0).unwrap();
+            return skip("not on btrfs");
+        }
+
+        let outfd = File::create(&to).unwrap();
+        allocate_file(&outfd, len).unwrap();
+        assert!(try_clone_range(&infd, 1024 * 4096, 4096, &outfd, 1024 * 4096).unwrap());
+        let bytes = read(&to).unwrap();
+        assert_eq!(&bytes[1024 * 4096..1024 * 4096 + 8], b"c00lc0d3");
+
+        // Into a destination that's kept, so each segment is cloned.
+        let opts = CopyOptions { presized: true, ..CopyOptions::default() };
+        let stats = copy_opts(&from, &to, &opts).unwrap();
+        assert_eq!(stats.bytes_total, len);
+        assert_eq!(stats.data_segments, 3);
+        assert_eq!(stats.method, CopyMethod::Kernel);
+        assert!(read(&from).unwrap() == read(&to).unwrap());
+    }
+
+    #[test]
+    fn test_reflink_tmpfs_fallback() {
+        let shm = Path::new("/dev/shm");
+        if !shm.is_dir() {
+            return skip("no /dev/shm");
+        }
+        let from = shm.join(format!("rust-reflink-{}-from", process::id()));
+        let to = shm.join(format!("rust-reflink-{}-to", process::id()));
+        let text = "This is a test file.";
+
+        {
+            let file = File::create(&from).unwrap();
+            write!(&file, "{}", text).unwrap();
+        }
+
+        {
+            let infd = File::open(&from).unwrap();
+            let outfd = File::create(&to).unwrap();
+            assert!(!try_reflink(&infd, &outfd).unwrap());
+        }
 
         let written = copy(&from, &to).unwrap();
-        assert_eq!(slen, written);
-        assert!(is_fsparse(&to).unwrap());
+        assert_eq!(text.len() as u64, written);
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
 
-        let from_data = read(&from).unwrap();
-        let to_data = read(&to).unwrap();
-        assert_eq!(from_data, to_data);
+        remove_file(&from).unwrap();
+        remove_file(&to).unwrap();
     }
 
     #[test]
@@ -677,4 +6396,44 @@ mod tests {
         let to_data = read(&to).unwrap();
         assert_eq!(from_data, to_data);
     }
+
+    #[test]
+    fn test_copy_cancellable() {
+        let dir = tmpdir();
+        let (from, to) = tmps(&dir);
+        let len = 8 * 1024 * 1024;
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&vec![0x5a; len]).unwrap();
+        }
+
+        // Throttled so that the copy is still running when cancelled.
+        let opts = CopyOptions {
+            max_bytes_per_sec: Some(2 * 1024 * 1024),
+            remove_partial: true,
+            ..CopyOptions::default()
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let err = copy_cancellable(&from, &to, &opts, &cancel).unwrap_err();
+        canceller.join().unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        assert!(err.bytes_copied < len as u64);
+        assert!(!to.exists());
+
+        // Without remove_partial the partial copy is left in place.
+        let opts = CopyOptions { remove_partial: false, ..opts };
+        let err = copy_cancellable(&from, &to, &opts, &cancel).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        assert!(to.exists());
+    }
 }